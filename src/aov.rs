@@ -0,0 +1,327 @@
+use crate::integrator::LightPaths;
+use crate::vec3::Vec3;
+use serde::{Serialize, Deserialize};
+
+/// Arbitrary output variable: a per-pixel buffer written alongside the
+/// beauty pass, for compositing and denoising.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aov {
+    /// Linear radiance, the image itself.
+    Beauty,
+    /// Surface colour at the first hit, with lighting taken out.
+    Albedo,
+    /// Shading normal at the first hit.
+    Normal,
+    /// Distance from the camera to the first hit.
+    Depth,
+    /// World-space position of the first hit.
+    Position,
+    /// Index of the object at the first hit, -1 for the background.
+    ObjectId,
+    DirectDiffuse,
+    IndirectDiffuse,
+    DirectSpecular,
+    IndirectSpecular,
+    /// Emitters and sky seen directly.
+    Emission,
+    /// Samples taken in each pixel, which adaptive sampling varies.
+    SampleCount,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 12] = [
+        Aov::Beauty,
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::ObjectId,
+        Aov::DirectDiffuse,
+        Aov::IndirectDiffuse,
+        Aov::DirectSpecular,
+        Aov::IndirectSpecular,
+        Aov::Emission,
+        Aov::SampleCount,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Beauty => "beauty",
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectSpecular => "indirect_specular",
+            Aov::Emission => "emission",
+            Aov::SampleCount => "sample_count",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+
+    /// Floats stored per pixel.
+    pub fn channels(&self) -> usize {
+        match self {
+            Aov::Depth | Aov::ObjectId | Aov::SampleCount => 1,
+            _ => 3,
+        }
+    }
+
+    /// True for the AOVs that split the radiance by how it reached the
+    /// camera, which only some integrators can fill.
+    pub fn is_light_path(&self) -> bool {
+        matches!(
+            self,
+            Aov::DirectDiffuse | Aov::IndirectDiffuse | Aov::DirectSpecular | Aov::IndirectSpecular | Aov::Emission
+        )
+    }
+
+    /// Channel names within the AOV's layer in image files.
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId => &["id"],
+            Aov::SampleCount => &["count"],
+            _ => &["R", "G", "B"],
+        }
+    }
+}
+
+/// What one camera sample saw at its first hit, for the AOVs describing the
+/// surface. The light path AOVs are only filled by integrators that can
+/// split their radiance.
+#[derive(Clone, Copy, Debug)]
+pub struct AovSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    pub position: Vec3,
+    pub object_id: Option<usize>,
+    pub paths: Option<LightPaths<Vec3>>,
+}
+
+impl AovSample {
+    /// Sample for a ray that hit nothing.
+    pub fn background() -> AovSample {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        AovSample {
+            albedo: zero,
+            normal: zero,
+            depth: 0.0,
+            position: zero,
+            object_id: None,
+            paths: None,
+        }
+    }
+}
+
+/// Per-pixel average of a pixel's `AovSample`s, except for the object ID,
+/// which can't be blended and comes from the first sample.
+pub struct AovPixel {
+    samples: u32,
+    albedo: Vec3,
+    normal: Vec3,
+    depth: f32,
+    position: Vec3,
+    object_id: Option<Option<usize>>,
+    paths: LightPaths<Vec3>,
+}
+
+impl AovPixel {
+    pub fn new() -> AovPixel {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        AovPixel {
+            samples: 0,
+            albedo: zero,
+            normal: zero,
+            depth: 0.0,
+            position: zero,
+            object_id: None,
+            paths: LightPaths::new(zero),
+        }
+    }
+
+    pub fn add(&mut self, sample: &AovSample) {
+        self.samples += 1;
+        self.albedo += sample.albedo;
+        self.normal += sample.normal;
+        self.depth += sample.depth;
+        self.position += sample.position;
+        self.object_id.get_or_insert(sample.object_id);
+        if let Some(paths) = sample.paths {
+            self.paths.emission += paths.emission;
+            self.paths.direct_diffuse += paths.direct_diffuse;
+            self.paths.indirect_diffuse += paths.indirect_diffuse;
+            self.paths.direct_specular += paths.direct_specular;
+            self.paths.indirect_specular += paths.indirect_specular;
+        }
+    }
+
+    /// Adds another pixel's samples to this one.
+    pub fn merge(&mut self, other: &AovPixel) {
+        self.samples += other.samples;
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+        self.position += other.position;
+        if self.object_id.is_none() {
+            self.object_id = other.object_id;
+        }
+        self.paths.emission += other.paths.emission;
+        self.paths.direct_diffuse += other.paths.direct_diffuse;
+        self.paths.indirect_diffuse += other.paths.indirect_diffuse;
+        self.paths.direct_specular += other.paths.direct_specular;
+        self.paths.indirect_specular += other.paths.indirect_specular;
+    }
+
+    /// Averaged value of `aov`, which must not be the beauty pass.
+    pub fn value(&self, aov: Aov) -> Vec<f32> {
+        let n = self.samples.max(1) as f32;
+        let rgb = |c: Vec3| {
+            let c = c / n;
+            vec![c.r(), c.g(), c.b()]
+        };
+        match aov {
+            Aov::Beauty => Vec::new(),
+            Aov::Albedo => rgb(self.albedo),
+            Aov::Normal => {
+                let normal = if self.normal.length() > 0.0 { self.normal.unit() } else { self.normal };
+                vec![normal.x(), normal.y(), normal.z()]
+            }
+            Aov::Depth => vec![self.depth / n],
+            Aov::Position => rgb(self.position),
+            Aov::ObjectId => vec![self.object_id.flatten().map_or(-1.0, |id| id as f32)],
+            Aov::DirectDiffuse => rgb(self.paths.direct_diffuse),
+            Aov::IndirectDiffuse => rgb(self.paths.indirect_diffuse),
+            Aov::DirectSpecular => rgb(self.paths.direct_specular),
+            Aov::IndirectSpecular => rgb(self.paths.indirect_specular),
+            Aov::Emission => rgb(self.paths.emission),
+            Aov::SampleCount => vec![self.samples as f32],
+        }
+    }
+}
+
+impl Default for AovPixel {
+    fn default() -> AovPixel {
+        AovPixel::new()
+    }
+}
+
+/// Named float buffers for one image, rows stored top to bottom. The beauty
+/// pass isn't kept here but accumulated in a `Film`.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    buffers: Vec<(Aov, Vec<f32>)>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, aovs: &[Aov]) -> Framebuffer {
+        let mut buffers: Vec<(Aov, Vec<f32>)> = Vec::new();
+        for aov in aovs {
+            if *aov != Aov::Beauty && buffers.iter().all(|(a, _)| a != aov) {
+                let len = (width * height) as usize * aov.channels();
+                buffers.push((*aov, vec![0.0; len]));
+            }
+        }
+        Framebuffer {
+            width,
+            height,
+            buffers,
+        }
+    }
+
+    /// The AOVs this framebuffer holds.
+    pub fn aovs(&self) -> impl Iterator<Item = Aov> + '_ {
+        self.buffers.iter().map(|(aov, _)| *aov)
+    }
+
+    pub fn get(&self, aov: Aov) -> Option<&[f32]> {
+        self.buffers.iter().find(|(a, _)| *a == aov).map(|(_, data)| &data[..])
+    }
+
+    /// One row of `aov`, with `y` counting up from the bottom of the image
+    /// like `Scene::image_row`.
+    pub fn row(&self, aov: Aov, y: u32) -> Option<&[f32]> {
+        let stride = self.width as usize * aov.channels();
+        let start = self.row_index(y)? * stride;
+        self.get(aov).map(|data| &data[start..start + stride])
+    }
+
+    /// Stores one pixel of `aov`. Pixels outside the image or AOVs the
+    /// framebuffer doesn't hold are ignored.
+    pub fn set(&mut self, aov: Aov, x: u32, y: u32, value: &[f32]) {
+        if x >= self.width {
+            return;
+        }
+        let row = match self.row_index(y) {
+            Some(row) => row,
+            None => return,
+        };
+        let channels = aov.channels();
+        let start = (row * self.width as usize + x as usize) * channels;
+        if let Some((_, data)) = self.buffers.iter_mut().find(|(a, _)| *a == aov) {
+            data[start..start + channels].copy_from_slice(&value[..channels]);
+        }
+    }
+
+    fn row_index(&self, y: u32) -> Option<usize> {
+        if y < self.height {
+            Some((self.height - 1 - y) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aov, Framebuffer};
+    use crate::hitable::HitList;
+    use crate::material::{Lambertian, Material};
+    use crate::scene::Scene;
+    use crate::settings::{IntegratorKind, RenderSettings};
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn rows_are_stored_top_down() {
+        let mut fb = Framebuffer::new(2, 3, &[Aov::Depth, Aov::Beauty, Aov::Albedo, Aov::Depth]);
+        assert_eq!(fb.aovs().collect::<Vec<_>>(), vec![Aov::Depth, Aov::Albedo]);
+        fb.set(Aov::Depth, 1, 0, &[5.0]);
+        fb.set(Aov::Albedo, 0, 2, &[1.0, 2.0, 3.0]);
+        assert_eq!(fb.get(Aov::Depth).unwrap(), &[0.0, 0.0, 0.0, 0.0, 0.0, 5.0]);
+        assert_eq!(&fb.row(Aov::Albedo, 2).unwrap()[..3], &[1.0, 2.0, 3.0]);
+        assert!(fb.get(Aov::Beauty).is_none());
+        assert!(fb.get(Aov::Normal).is_none());
+        assert_eq!(Aov::from_name("object_id"), Some(Aov::ObjectId));
+    }
+
+    #[test]
+    fn light_paths_need_an_integrator_that_splits_them() {
+        let scene = |integrator: IntegratorKind| {
+            let ball = Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, Material::Lambertian { mat: Lambertian::new(0.5, 0.5, 0.5) });
+            let settings = RenderSettings {
+                integrator,
+                aovs: vec![Aov::Albedo, Aov::DirectDiffuse, Aov::Emission],
+                ..RenderSettings::default()
+            };
+            Scene::from_world(4, 2, HitList { list: vec![ball] }, settings)
+        };
+        let path = scene(IntegratorKind::Path);
+        assert!(path.aov("albedo").is_some() && path.aov("direct_diffuse").is_some() && path.aov("emission").is_some());
+        for integrator in [IntegratorKind::Bdpt, IntegratorKind::Photon, IntegratorKind::Mlt].iter() {
+            let other = scene(*integrator);
+            assert!(other.aov("albedo").is_some());
+            assert!(other.aov("direct_diffuse").is_none() && other.aov("emission").is_none());
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use crate::camera::Camera;
+use crate::film::LightSplat;
+use crate::hitable::{HitList, HitRecord, Hitable};
+use crate::integrator::{background, leaks, shading_frame};
+use crate::material::{Bsdf, Subsurface};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::settings::RenderSettings;
+use crate::spectrum::{ColorMode, Spectrum};
+use crate::vec3::{random_cosine_direction, Vec3};
+use std::f32::consts::PI;
+
+/// Bidirectional path tracer. A camera subpath and a light subpath are traced
+/// for each sample, every pair of their vertices is connected, and the
+/// resulting strategies are weighted against each other with the balance
+/// heuristic.
+///
+/// Light subpath vertices are also joined straight to a point on the lens.
+/// That light can land anywhere in the picture, so it goes to the film as
+/// splats instead of into the sample's own pixel.
+pub struct Bdpt {
+    max_depth: u32,
+    rr_depth: u32,
+    camera: Camera,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Camera,
+    Light,
+    Surface,
+}
+
+struct Vertex<'a, V> {
+    kind: Kind,
+    p: Vec3,
+    /// Geometric normal, zero at the camera.
+    n: Vec3,
+    rec: Option<HitRecord<'a>>,
+    /// Shading frame and the direction back along the subpath in it.
+    uvw: Onb,
+    wo: Vec3,
+    /// Subpath throughput up to and including this vertex.
+    beta: V,
+    /// Set when the vertex scattered through a specular lobe, so it can't be
+    /// connected to.
+    delta: bool,
+    /// Area densities of this vertex being sampled from its predecessor and,
+    /// the other way round, from its successor.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl<'a, V: Spectrum> Vertex<'a, V> {
+    fn camera(r: &Ray, beta: V) -> Vertex<'a, V> {
+        Vertex {
+            kind: Kind::Camera,
+            p: r.origin(),
+            n: Vec3::new(0.0, 0.0, 0.0),
+            rec: None,
+            uvw: Onb::from_w(&r.direction()),
+            wo: Vec3::new(0.0, 0.0, 1.0),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(rec: HitRecord<'a>, beta: V, pdf: f32) -> Vertex<'a, V> {
+        Vertex {
+            kind: Kind::Light,
+            p: rec.p,
+            n: rec.geometric_normal,
+            uvw: Onb::from_w(&rec.geometric_normal),
+            rec: Some(rec),
+            wo: Vec3::new(0.0, 0.0, 1.0),
+            beta,
+            delta: false,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(rec: HitRecord<'a>, uvw: Onb, wo: Vec3, beta: V) -> Vertex<'a, V> {
+        Vertex {
+            kind: Kind::Surface,
+            p: rec.p,
+            n: rec.geometric_normal,
+            rec: Some(rec),
+            uvw,
+            wo,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn le(&self) -> Vec3 {
+        match &self.rec {
+            Some(rec) => rec.material.emitted(rec),
+            None => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Cosine between the shading normal and `w`, one at the camera.
+    fn cos(&self, w: &Vec3) -> f32 {
+        match self.kind {
+            Kind::Camera => 1.0,
+            _ => self.uvw.to_local(w).z().abs(),
+        }
+    }
+
+    /// BSDF for light scattering between this vertex's predecessor and `next`.
+    /// Light subpaths carry importance, which needs a correction wherever the
+    /// shading normal differs from the true one.
+    fn f(&self, next: &Vertex<V>, importance: bool) -> Vec3 {
+        let rec = match (&self.rec, self.kind) {
+            (Some(rec), Kind::Surface) => rec,
+            _ => return Vec3::new(0.0, 0.0, 0.0),
+        };
+        let direction = (next.p - self.p).unit();
+        let wi = self.uvw.to_local(&direction);
+        let toward = self.uvw.to_world(&self.wo);
+        if leaks(rec, &toward, &direction, &self.wo, &wi) {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let f = rec.material.eval(rec, &self.wo, &wi);
+        if importance {
+            f * self.shading_correction(&toward, &direction)
+        } else {
+            f
+        }
+    }
+
+    fn shading_correction(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let numerator = self.cos(wo) * wi.dot(&self.n).abs();
+        let denominator = wo.dot(&self.n).abs() * self.cos(wi);
+        if denominator == 0.0 {
+            0.0
+        } else {
+            numerator / denominator
+        }
+    }
+
+    /// Turns a solid-angle density at this vertex into an area density at `next`.
+    fn convert_density(&self, pdf: f32, next: &Vertex<V>) -> f32 {
+        let w = next.p - self.p;
+        let distance_squared = w.squared_length();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+        let cos = match next.kind {
+            Kind::Camera => 1.0,
+            _ => next.n.dot(&w).abs() / distance_squared.sqrt(),
+        };
+        pdf * cos / distance_squared
+    }
+
+    /// Area density of sampling `next` from this vertex, having arrived from
+    /// `prev`. Without a predecessor the vertex is treated as a light.
+    fn pdf(&self, prev: Option<&Vertex<V>>, next: &Vertex<V>) -> f32 {
+        let prev = match (self.kind, prev) {
+            (Kind::Camera, _) => return 0.0,
+            (Kind::Light, _) | (_, None) => return self.pdf_light(next),
+            (Kind::Surface, Some(prev)) => prev,
+        };
+        let rec = self.rec.as_ref().unwrap();
+        let wo = self.uvw.to_local(&(prev.p - self.p).unit());
+        let wi = self.uvw.to_local(&(next.p - self.p).unit());
+        self.convert_density(rec.material.pdf(rec, &wo, &wi), next)
+    }
+
+    /// Area density of a light emitting from this vertex towards `next`.
+    /// Light subpaths leave from the outside of the light only.
+    fn pdf_light(&self, next: &Vertex<V>) -> f32 {
+        let cos = self.n.dot(&(next.p - self.p).unit());
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        self.convert_density(cos / PI, next)
+    }
+
+    /// Area density of a light subpath starting at this vertex.
+    fn pdf_light_origin<T: Hitable>(&self, lights: &[&T]) -> f32 {
+        if lights.is_empty() {
+            return 0.0;
+        }
+        lights.iter().map(|l| l.surface_pdf(&self.p)).sum::<f32>() / lights.len() as f32
+    }
+}
+
+impl Bdpt {
+    pub fn new(settings: &RenderSettings, camera: Camera) -> Bdpt {
+        Bdpt {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+            camera,
+        }
+    }
+
+    /// Radiance along `r`, with the light that this sample's light subpath
+    /// brings straight to the lens added to `splats`.
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<LightSplat<C::Value>>,
+    ) -> C::Value {
+        let mut radiance = mode.constant(0.0);
+
+        let mut camera_path = vec![Vertex::camera(&r, mode.constant(1.0))];
+        // Nothing samples the sky, so the camera subpath is the only way to
+        // reach it and it counts in full
+        let beta = mode.constant(1.0);
+        let pdf_dir = self.camera.pdf_dir(&r.direction());
+        if let Some(sky) = self.random_walk(world, r, beta, pdf_dir, false, mode, sampler, &mut camera_path) {
+            radiance += sky;
+        }
+
+        let mut light_path = Vec::new();
+        self.light_subpath(world, lights, mode, sampler, &mut light_path);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                // An emitter joined straight to the lens is the camera
+                // subpath seeing it, which counts in full
+                if t == 1 && s < 2 {
+                    continue;
+                }
+                if s + t - 2 > self.max_depth as usize {
+                    break;
+                }
+                if t == 1 {
+                    splats.extend(self.splat(world, lights, &light_path, s, mode, sampler));
+                } else {
+                    radiance += self.connect(world, lights, &light_path, &camera_path, s, t, mode);
+                }
+            }
+        }
+        radiance
+    }
+
+    fn light_subpath<'a, T: Hitable, C: ColorMode>(
+        &self,
+        world: &'a HitList<T>,
+        lights: &[&'a T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex<'a, C::Value>>,
+    ) {
+        if lights.is_empty() {
+            return;
+        }
+        let index = ((sampler.next_1d() * lights.len() as f32) as usize).min(lights.len() - 1);
+        let (rec, pdf_pos) = match lights[index].sample_surface(sampler) {
+            Some(sample) => sample,
+            None => return,
+        };
+        let pdf_pos = pdf_pos / lights.len() as f32;
+        if pdf_pos <= 0.0 {
+            return;
+        }
+        let le = rec.material.emitted(&rec);
+        let light = Vertex::light(rec, mode.constant(1.0 / pdf_pos), pdf_pos);
+        let local = random_cosine_direction(sampler);
+        let pdf_dir = local.z() / PI;
+        if pdf_dir <= 0.0 {
+            return;
+        }
+        let r = Ray::new(light.p, light.uvw.to_world(&local));
+        let beta = mode.rgb(le) * (local.z() / (pdf_pos * pdf_dir));
+        path.push(light);
+        self.random_walk(world, r, beta, pdf_dir, true, mode, sampler, path);
+    }
+
+    /// Extends a subpath from its last vertex along `r`. Returns the sky seen
+    /// by a camera subpath that escapes the scene.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a, T: Hitable, C: ColorMode>(
+        &self,
+        world: &'a HitList<T>,
+        r: Ray,
+        mut beta: C::Value,
+        mut pdf_fwd: f32,
+        importance: bool,
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex<'a, C::Value>>,
+    ) -> Option<C::Value> {
+        let mut ray = r;
+        let mut medium: Option<&Subsurface> = None;
+        let mut bounces = 0;
+
+        while bounces < self.max_depth {
+            let mut x = match world.hit(&ray, 0.001, f32::MAX) {
+                Some(x) => x,
+                None if importance => return None,
+                None => return Some(beta * mode.rgb(background(&ray))),
+            };
+
+            // A walk through a medium sits between two specular boundary
+            // vertices, so no connection or density ever depends on it
+            if let Some(m) = medium {
+                let length = ray.direction().length();
+                let flight = m.sample_free_flight(x.t * length, sampler);
+                beta = beta * mode.rgb(flight.weight);
+                if flight.scattered {
+                    bounces += 1;
+                    let p = ray.point_at_parameter(flight.distance / length);
+                    ray = Ray::new(p, m.sample_phase(sampler));
+                    continue;
+                }
+            }
+
+            x.wavelength = mode.wavelength();
+            let (uvw, wo) = shading_frame(&mut x, &ray);
+            let mut vertex = Vertex::surface(x, uvw, wo, beta);
+            let k = path.len();
+            vertex.pdf_fwd = path[k - 1].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            bounces += 1;
+            if bounces >= self.max_depth {
+                break;
+            }
+
+            let vertex = &path[k];
+            let x = vertex.rec.as_ref().unwrap();
+            let s = match x.material.sample(x, &wo, sampler) {
+                Some(s) => s,
+                None => break,
+            };
+            let direction = vertex.uvw.to_world(&s.wi);
+            let toward = -ray.direction().unit();
+            if leaks(x, &toward, &direction, &wo, &s.wi) {
+                break;
+            }
+            if s.dispersive {
+                mode.terminate_secondary();
+            }
+            let mut weight = s.weight();
+            if importance {
+                weight *= vertex.shading_correction(&toward, &direction);
+            }
+            beta = beta * mode.rgb(weight);
+            let pdf_rev = if s.specular {
+                pdf_fwd = 0.0;
+                0.0
+            } else {
+                pdf_fwd = s.pdf;
+                x.material.pdf(x, &s.wi, &wo)
+            };
+            medium = if direction.dot(&x.geometric_normal) < 0.0 {
+                x.material.medium()
+            } else {
+                None
+            };
+            let p = x.p;
+            let pdf_rev = path[k].convert_density(pdf_rev, &path[k - 1]);
+            path[k - 1].pdf_rev = pdf_rev;
+            path[k].delta = s.specular;
+            ray = Ray::new(p, direction);
+
+            if bounces >= self.rr_depth {
+                let survive = beta.max_value().min(0.95);
+                if survive <= 0.0 || sampler.next_1d() >= survive {
+                    break;
+                }
+                beta = beta * (1.0 / survive);
+            }
+        }
+        None
+    }
+
+    /// Contribution of the path made of the first `s` light subpath vertices
+    /// and the first `t` camera subpath vertices, already weighted.
+    #[allow(clippy::too_many_arguments)]
+    fn connect<T: Hitable, C: ColorMode>(
+        &self,
+        world: &HitList<T>,
+        lights: &[&T],
+        light_path: &[Vertex<C::Value>],
+        camera_path: &[Vertex<C::Value>],
+        s: usize,
+        t: usize,
+        mode: &C,
+    ) -> C::Value {
+        let none = mode.constant(0.0);
+        let pt = &camera_path[t - 1];
+        let l = if s == 0 {
+            let le = pt.le();
+            if le.max_value() <= 0.0 {
+                return none;
+            }
+            pt.beta * mode.rgb(le)
+        } else {
+            let qs = &light_path[s - 1];
+            if qs.delta || pt.delta {
+                return none;
+            }
+            let w = qs.p - pt.p;
+            let distance = w.length();
+            if distance == 0.0 {
+                return none;
+            }
+            let w = w / distance;
+            let g = qs.cos(&w) * pt.cos(&w) / (distance * distance);
+            let f = if s == 1 {
+                qs.le() * pt.f(qs, false)
+            } else {
+                qs.f(pt, true) * pt.f(qs, false)
+            };
+            if f.max_value() <= 0.0 || g <= 0.0 {
+                return none;
+            }
+            let shadow = Ray::new(pt.p, w);
+            if world.hit(&shadow, 0.001, distance - 0.001).is_some() {
+                return none;
+            }
+            qs.beta * pt.beta * mode.rgb(f) * g
+        };
+        l * mis_weight(lights, &self.camera, light_path, camera_path, s, t, None)
+    }
+
+    /// Joins the first `s` light subpath vertices to a point on the lens,
+    /// returning the weighted light they bring and where on the film it
+    /// lands.
+    fn splat<T: Hitable, C: ColorMode>(
+        &self,
+        world: &HitList<T>,
+        lights: &[&T],
+        light_path: &[Vertex<C::Value>],
+        s: usize,
+        mode: &C,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSplat<C::Value>> {
+        let qs = &light_path[s - 1];
+        if qs.delta {
+            return None;
+        }
+        let lens = self.camera.sample_lens(sampler);
+        let (u, v) = self.camera.film_position(&lens, &qs.p)?;
+        let w = lens - qs.p;
+        let distance = w.length();
+        if distance == 0.0 {
+            return None;
+        }
+        let w = w / distance;
+        let pt = Vertex::camera(&Ray::new(lens, -w), mode.constant(1.0));
+        let f = qs.f(&pt, true) * qs.cos(&w);
+        if f.max_value() <= 0.0 {
+            return None;
+        }
+        let shadow = Ray::new(qs.p, w);
+        if world.hit(&shadow, 0.001, distance - 0.001).is_some() {
+            return None;
+        }
+        // The camera's importance over the density of picking this lens
+        // point from `qs` comes down to the density of its rays' directions
+        let importance = self.camera.pdf_dir(&-w) / (distance * distance);
+        let weight = mis_weight(lights, &self.camera, light_path, &[], s, 1, Some(&pt));
+        Some(LightSplat {
+            u,
+            v,
+            value: qs.beta * mode.rgb(f) * (importance * weight),
+        })
+    }
+}
+
+/// Balance heuristic weight of strategy (`s`, `t`) among every other way the
+/// same path could have been sampled, found by walking the density ratios out
+/// from the connection in both directions. With `t` of one the camera vertex
+/// is the `sampled` lens point rather than the start of `camera_path`.
+#[allow(clippy::too_many_arguments)]
+fn mis_weight<T: Hitable, V: Spectrum>(
+    lights: &[&T],
+    camera: &Camera,
+    light_path: &[Vertex<V>],
+    camera_path: &[Vertex<V>],
+    s: usize,
+    t: usize,
+    sampled: Option<&Vertex<V>>,
+) -> f32 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    let pt = sampled.unwrap_or_else(|| &camera_path[t - 1]);
+    let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+    let qs = if s > 0 { Some(&light_path[s - 1]) } else { None };
+    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+
+    // Reverse densities of the vertices next to the connection, which the
+    // subpaths couldn't fill in when they were traced
+    let pt_rev = match qs {
+        Some(qs) => qs.pdf(qs_minus, pt),
+        None => pt.pdf_light_origin(lights),
+    };
+    let pt_minus_rev = match (qs, pt_minus) {
+        (Some(qs), Some(pt_minus)) => pt.pdf(Some(qs), pt_minus),
+        (None, Some(pt_minus)) => pt.pdf_light(pt_minus),
+        (_, None) => 0.0,
+    };
+    // The lens has no BSDF, only the camera's density of ray directions
+    let qs_rev = match qs {
+        Some(qs) if t == 1 => pt.convert_density(camera.pdf_dir(&(qs.p - pt.p)), qs),
+        Some(qs) => pt.pdf(pt_minus, qs),
+        None => 0.0,
+    };
+    let qs_minus_rev = match (qs, qs_minus) {
+        (Some(qs), Some(qs_minus)) => qs.pdf(Some(pt), qs_minus),
+        _ => 0.0,
+    };
+
+    let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+
+    let mut ri = 1.0;
+    // Stops short of the camera vertex itself, since light subpaths never
+    // hit the lens on their own
+    for i in (1..t).rev() {
+        let rev = if i == t - 1 {
+            pt_rev
+        } else if i == t - 2 {
+            pt_minus_rev
+        } else {
+            camera_path[i].pdf_rev
+        };
+        ri *= remap(rev) / remap(camera_path[i].pdf_fwd);
+        let delta = i != t - 1 && camera_path[i].delta;
+        if !delta && !camera_path[i - 1].delta {
+            sum += ri;
+        }
+    }
+
+    let mut ri = 1.0;
+    for i in (0..s).rev() {
+        let rev = if i == s - 1 {
+            qs_rev
+        } else if i == s - 2 {
+            qs_minus_rev
+        } else {
+            light_path[i].pdf_rev
+        };
+        ri *= remap(rev) / remap(light_path[i].pdf_fwd);
+        let delta = i != s - 1 && light_path[i].delta;
+        let delta_before = i > 0 && light_path[i - 1].delta;
+        if !delta && !delta_before {
+            sum += ri;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mis_weight, Vertex};
+    use crate::camera::Camera;
+    use crate::hitable::{HitList, Hitable};
+    use crate::integrator::shading_frame;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::ray::Ray;
+    use crate::scene::Scene;
+    use crate::settings::{IntegratorKind, RenderSettings};
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    fn world() -> HitList<Sphere> {
+        let mut w = HitList { list: Vec::new() };
+        w.list.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::Lambertian { mat: Lambertian::new(0.5, 0.5, 0.5) }));
+        w.list.push(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, Material::Lambertian { mat: Lambertian::new(0.8, 0.3, 0.3) }));
+        w.list.push(Sphere::new(Vec3::new(3.0, 4.0, 1.0), 0.5, Material::DiffuseLight { mat: DiffuseLight::new(40.0, 40.0, 40.0) }));
+        w
+    }
+
+    #[test]
+    fn matches_path_tracer() {
+        let (width, height) = (32, 16);
+        let render = |integrator: IntegratorKind| {
+            let settings = RenderSettings { integrator, ..RenderSettings::default() };
+            let mut scene = Scene::from_world(width, height, world(), settings);
+            for _ in 0..256 {
+                scene.render_pass();
+            }
+            scene.aov("beauty").unwrap()
+        };
+        let bdpt = render(IntegratorKind::Bdpt);
+        let path = render(IntegratorKind::Path);
+        // Light splatted to the wrong place or with the wrong weight shows
+        // up as one part of the picture disagreeing
+        let block = |image: &[f32], bx: u32, by: u32| -> f32 {
+            let mut sum = 0.0;
+            for y in by * 8..by * 8 + 8 {
+                for x in bx * 8..bx * 8 + 8 {
+                    let i = ((y * width + x) * 3) as usize;
+                    sum += image[i] + image[i + 1] + image[i + 2];
+                }
+            }
+            sum / 192.0
+        };
+        for by in 0..2 {
+            for bx in 0..4 {
+                let (a, b) = (block(&bdpt, bx, by), block(&path, bx, by));
+                assert!((a - b).abs() < 0.02 * b, "block {} {}: {} vs {}", bx, by, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_of_every_strategy_sum_to_one() {
+        let world = world();
+        let lights = [&world.list[2]];
+        let lookfrom = Vec3::new(16.0, 2.0, 4.0);
+        let camera = Camera::new(lookfrom, Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 15.0, 2.0, 0.2, 10.0);
+
+        // Camera, floor, red ball, light
+        let mut rays = Vec::new();
+        let mut points = vec![lookfrom];
+        for target in [Vec3::new(1.5, 0.0, 1.5), Vec3::new(0.0, 1.0, 0.0), Vec3::new(3.0, 4.0, 1.0)].iter() {
+            let from = *points.last().unwrap();
+            let r = Ray::new(from, *target - from);
+            points.push(world.hit(&r, 0.001, f32::MAX).unwrap().p);
+            rays.push(r);
+        }
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        let surface = |i: usize| {
+            let mut rec = world.hit(&rays[i], 0.001, f32::MAX).unwrap();
+            let (uvw, wo) = shading_frame(&mut rec, &rays[i]);
+            Vertex::surface(rec, uvw, wo, one)
+        };
+        assert!(surface(2).le().x() > 0.0);
+
+        // Densities filled in as the random walks would
+        let chain = |path: &mut Vec<Vertex<Vec3>>, first: f32| {
+            for k in 1..path.len() {
+                let pdf = if k == 1 { first } else { path[k - 1].pdf(Some(&path[k - 2]), &path[k]) };
+                path[k].pdf_fwd = pdf;
+            }
+            for k in 0..path.len().saturating_sub(2) {
+                let pdf = path[k + 1].pdf(Some(&path[k + 2]), &path[k]);
+                path[k].pdf_rev = pdf;
+            }
+        };
+        let mut camera_path = vec![Vertex::camera(&Ray::new(lookfrom, points[1] - lookfrom), one)];
+        camera_path.extend((0..3).map(surface));
+        let first = camera_path[0].convert_density(camera.pdf_dir(&(points[1] - lookfrom)), &camera_path[1]);
+        chain(&mut camera_path, first);
+        let rec = world.hit(&rays[2], 0.001, f32::MAX).unwrap();
+        let pdf_pos = lights[0].surface_pdf(&rec.p);
+        let mut light_path = vec![Vertex::light(rec, one, pdf_pos)];
+        light_path.extend((0..2).rev().map(surface));
+        let first = light_path[0].pdf_light(&light_path[1]);
+        chain(&mut light_path, first);
+
+        let lens = Vertex::camera(&Ray::new(lookfrom, points[1] - lookfrom), one);
+        let mut total = 0.0;
+        for s in 0..=3 {
+            let t = 4 - s;
+            let sampled = if t == 1 { Some(&lens) } else { None };
+            let weight = mis_weight(&lights, &camera, &light_path[..s], &camera_path[..t], s, t, sampled);
+            assert!(weight > 0.0 && weight < 1.0, "({}, {}) {}", s, t, weight);
+            total += weight;
+        }
+        assert!((total - 1.0).abs() < 1e-4, "{}", total);
+    }
+}
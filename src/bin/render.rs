@@ -0,0 +1,63 @@
+//! Renders the random sphere scene natively and writes it in the format
+//! given by the output file's extension.
+//!
+//!     render <out.{hdr,exr,png,ppm,pfm}> [width] [height] [settings.json]
+//!            [--float] [--no-zip] [--16] [--demo]
+//!
+//! `--demo` renders the material showcase scene instead.
+//! EXR output holds every AOV listed in the settings, as half floats with
+//! ZIP compression unless told otherwise. PNGs are 8-bit unless `--16` is
+//! given.
+
+use rust_raytracer::{demo_scene, random_scene};
+use rust_raytracer::scene::Scene;
+use rust_raytracer::settings::RenderSettings;
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let float = args.iter().any(|a| a == "--float");
+    let zip = !args.iter().any(|a| a == "--no-zip");
+    let sixteen_bit = args.iter().any(|a| a == "--16");
+    let demo = args.iter().any(|a| a == "--demo");
+    let positional: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    if positional.is_empty() {
+        eprintln!(
+            "usage: render <out.{{hdr,exr,png,ppm,pfm}}> [width] [height] [settings.json] [--float] [--no-zip] [--16] [--demo]"
+        );
+        process::exit(2);
+    }
+    let out = positional[0];
+    let width: u32 = positional.get(1).map_or(200, |w| w.parse().expect("width"));
+    let height: u32 = positional.get(2).map_or(100, |h| h.parse().expect("height"));
+    let settings: RenderSettings = match positional.get(3) {
+        Some(path) => {
+            let json = fs::read_to_string(path).expect("reading settings");
+            serde_json::from_str(&json).expect("parsing settings")
+        }
+        None => RenderSettings::default(),
+    };
+
+    let world = if demo { demo_scene() } else { random_scene() };
+    let mut scene = Scene::from_world(width, height, world, settings);
+    for y in 0..height {
+        scene.image_row(y);
+    }
+    let bytes = if out.ends_with(".exr") {
+        scene.exr_image(!float, zip)
+    } else if out.ends_with(".hdr") {
+        scene.hdr_image()
+    } else if out.ends_with(".png") {
+        scene.png_image(sixteen_bit)
+    } else if out.ends_with(".ppm") {
+        scene.ppm_image()
+    } else if out.ends_with(".pfm") {
+        scene.pfm_image()
+    } else {
+        eprintln!("unknown image format: {}", out);
+        process::exit(2);
+    };
+    fs::write(out, bytes).expect("writing image");
+}
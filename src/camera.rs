@@ -1,17 +1,17 @@
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::Vec3;
-use rand::prelude::*;
 use std::f32::consts::PI;
 
-pub fn random_in_unit_disk() -> Vec3 {
-    let mut p = Vec3::new(1.0, 1.0, 1.0);
-    let mut rng = thread_rng();
-    while p.dot(&p) >= 1.0 {
-        p = 2.0 * Vec3::new(rng.gen(), rng.gen(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
-    }
-    p
+/// Uniform point on the unit disk in the xy plane.
+pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let r = r1.sqrt();
+    let phi = 2.0 * PI * r2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), 0.0)
 }
 
+#[derive(Clone)]
 pub struct Camera {
     lower_left_corner: Vec3,
     horizontal: Vec3,
@@ -20,6 +20,9 @@ pub struct Camera {
     lens_radius: f32,
     u: Vec3,
     v: Vec3,
+    /// Points back out of the lens, away from the scene.
+    w: Vec3,
+    focus_dist: f32,
 }
 
 impl Camera {
@@ -52,15 +55,93 @@ impl Camera {
             lens_radius,
             u,
             v,
+            w,
+            focus_dist,
+        }
+    }
+
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let lens = self.sample_lens(sampler);
+        Ray::new(lens, self.lower_left_corner + s * self.horizontal + t * self.vertical - lens)
+    }
+
+    /// Uniform point on the lens.
+    pub fn sample_lens(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let rd: Vec3 = self.lens_radius * random_in_unit_disk(sampler);
+        self.origin + self.u * rd.x() + self.v * rd.y()
+    }
+
+    /// Film position `(s, t)` whose rays leave lens point `lens` towards `p`,
+    /// or `None` if `p` is behind the camera or outside the picture.
+    pub fn film_position(&self, lens: &Vec3, p: &Vec3) -> Option<(f32, f32)> {
+        let d = (*p - *lens).unit();
+        let cos = -d.dot(&self.w);
+        if cos <= 0.0 {
+            return None;
+        }
+        // Every ray through one film position meets at the focus plane
+        let on_plane = *lens + d * (self.focus_dist / cos) - self.lower_left_corner;
+        let s = on_plane.dot(&self.horizontal) / self.horizontal.squared_length();
+        let t = on_plane.dot(&self.vertical) / self.vertical.squared_length();
+        if (0.0..1.0).contains(&s) && (0.0..1.0).contains(&t) {
+            Some((s, t))
+        } else {
+            None
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd: Vec3 = self.lens_radius * random_in_unit_disk();
-        let offset: Vec3 = self.u * rd.x() + self.v * rd.y();
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
-        )
+    /// Solid angle density of `get_ray` leaving a lens point in direction
+    /// `d` when the film position is uniform over the whole picture.
+    pub fn pdf_dir(&self, d: &Vec3) -> f32 {
+        let cos = -d.unit().dot(&self.w);
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        // The picture's area on a plane a unit distance in front of the lens
+        let area = self.horizontal.length() * self.vertical.length() / (self.focus_dist * self.focus_dist);
+        1.0 / (area * cos * cos * cos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Camera;
+    use crate::sampler::Independent;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn film_position_inverts_get_ray() {
+        let camera = Camera::new(
+            Vec3::new(16.0, 2.0, 4.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            15.0,
+            2.0,
+            0.2,
+            10.0,
+        );
+        let mut sampler = Independent::new();
+        for (s, t) in [(0.1, 0.2), (0.5, 0.5), (0.93, 0.71)].iter() {
+            let r = camera.get_ray(*s, *t, &mut sampler);
+            let (s2, t2) = camera.film_position(&r.origin(), &r.point_at_parameter(3.0)).unwrap();
+            assert!((s - s2).abs() < 1e-4 && (t - t2).abs() < 1e-4);
+        }
+        assert!(camera.film_position(&Vec3::new(16.0, 2.0, 4.0), &Vec3::new(20.0, 2.0, 4.0)).is_none());
+
+        // Directions through the whole picture integrate to one
+        let n = 400;
+        let mut total = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let (s, t) = ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                let on_plane = camera.lower_left_corner + s * camera.horizontal + t * camera.vertical;
+                let d = on_plane - camera.origin;
+                // Solid angle of this bit of the focus plane
+                let area = camera.horizontal.length() * camera.vertical.length() / (n * n) as f32;
+                let cos = -d.unit().dot(&camera.w);
+                total += camera.pdf_dir(&d) * area * cos / d.squared_length();
+            }
+        }
+        assert!((total - 1.0).abs() < 1e-3, "{}", total);
     }
 }
@@ -0,0 +1,169 @@
+use crate::hitable::{HitList, Hitable};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::settings::{IntegratorKind, RenderSettings};
+use crate::spectrum::ColorMode;
+use crate::vec3::{random_cosine_direction, Vec3};
+
+/// Diagnostic views of the first surface the camera sees: ambient occlusion,
+/// normals, depth, texture coordinates, material and primitive IDs, and a
+/// heatmap of how many intersection tests each ray costs. The colours are
+/// meant to be shown as they are, without gamma.
+pub struct DebugIntegrator {
+    view: IntegratorKind,
+    ao_radius: f32,
+    /// Distance shown as mid grey in the depth view.
+    depth_scale: f32,
+    /// Index of each primitive's material among the distinct materials.
+    material_ids: Vec<u32>,
+}
+
+impl DebugIntegrator {
+    pub fn new(settings: &RenderSettings) -> DebugIntegrator {
+        DebugIntegrator {
+            view: settings.integrator,
+            ao_radius: settings.ao_radius,
+            depth_scale: 1.0,
+            material_ids: Vec::new(),
+        }
+    }
+
+    /// Numbers the distinct materials in `world` and scales depth so the
+    /// focus distance lands in the middle of the range.
+    pub fn preprocess<T: Hitable>(&mut self, world: &HitList<T>, focus: (Vec3, f32)) {
+        self.depth_scale = focus.1.max(1e-3);
+        if self.view != IntegratorKind::MaterialId {
+            return;
+        }
+        // Materials have no identity of their own, so equal ones are found
+        // by comparing their serialized form
+        let mut seen: Vec<String> = Vec::new();
+        self.material_ids = world
+            .list
+            .iter()
+            .map(|item| {
+                let key = item
+                    .primitive_material()
+                    .and_then(|m| serde_json::to_string(m).ok())
+                    .unwrap_or_default();
+                match seen.iter().position(|s| *s == key) {
+                    Some(id) => id as u32,
+                    None => {
+                        seen.push(key);
+                        seen.len() as u32 - 1
+                    }
+                }
+            })
+            .collect();
+    }
+
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> C::Value {
+        mode.rgb(self.color(&r, world, sampler))
+    }
+
+    fn color<T: Hitable>(&self, r: &Ray, world: &HitList<T>, sampler: &mut dyn Sampler) -> Vec3 {
+        if self.view == IntegratorKind::TraversalCost {
+            return heat(world.traversal_cost(r, 0.001, f32::MAX), world.list.len());
+        }
+        let (index, x) = match world.hit_index(r, 0.001, f32::MAX) {
+            Some(hit) => hit,
+            None => {
+                return match self.view {
+                    IntegratorKind::AmbientOcclusion | IntegratorKind::Depth => Vec3::new(1.0, 1.0, 1.0),
+                    _ => Vec3::new(0.0, 0.0, 0.0),
+                }
+            }
+        };
+        match self.view {
+            IntegratorKind::AmbientOcclusion => {
+                let wo = -r.direction();
+                let mut normal = x.material.shading_normal(&x);
+                if normal.dot(&wo) < 0.0 {
+                    normal = -normal;
+                }
+                let direction = Onb::from_w(&normal).to_world(&random_cosine_direction(sampler));
+                let probe = Ray::new(x.p, direction);
+                let open = match world.hit(&probe, 0.001, self.ao_radius) {
+                    Some(_) => 0.0,
+                    None => 1.0,
+                };
+                Vec3::new(open, open, open)
+            }
+            IntegratorKind::ShadingNormal => normal_color(&x.material.shading_normal(&x)),
+            IntegratorKind::GeometricNormal => normal_color(&x.geometric_normal),
+            IntegratorKind::Depth => {
+                let distance = x.t * r.direction().length();
+                let d = (distance / (2.0 * self.depth_scale)).min(1.0);
+                Vec3::new(d, d, d)
+            }
+            IntegratorKind::Uv => Vec3::new(x.u, x.v, 0.0),
+            IntegratorKind::MaterialId => id_color(self.material_ids.get(index).copied().unwrap_or(0)),
+            IntegratorKind::PrimitiveId => id_color(index as u32),
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+fn normal_color(n: &Vec3) -> Vec3 {
+    0.5 * (*n + Vec3::new(1.0, 1.0, 1.0))
+}
+
+/// Distinct, reasonably bright colour for each ID.
+fn id_color(id: u32) -> Vec3 {
+    let mut h = id.wrapping_add(1).wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    let channel = |shift: u32| 0.2 + 0.8 * ((h >> shift) & 0xff) as f32 / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
+
+/// Blue through green to red as `count` goes from none to `max`, on a log
+/// scale so small counts stay visible.
+pub fn heat(count: u32, max: usize) -> Vec3 {
+    let t = (1.0 + count as f32).ln() / (1.0 + max.max(1) as f32).ln();
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        let s = t * 2.0;
+        Vec3::new(0.0, s, 1.0 - s)
+    } else {
+        let s = (t - 0.5) * 2.0;
+        Vec3::new(s, 1.0 - s, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::heat;
+    use crate::hitable::{HitList, Hitable};
+    use crate::material::{Lambertian, Material};
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn traversal_cost_counts_bounds_entered() {
+        let m = || Material::Lambertian {
+            mat: Lambertian::new(0.5, 0.5, 0.5),
+        };
+        let world = HitList {
+            list: vec![
+                Sphere::new(Vec3::new(0.0, 0.0, -2.0), 0.5, m()),
+                Sphere::new(Vec3::new(0.0, 0.0, -4.0), 0.5, m()),
+                Sphere::new(Vec3::new(3.0, 0.0, -2.0), 0.5, m()),
+            ],
+        };
+        let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(world.traversal_cost(&r, 0.001, f32::MAX), 2);
+        assert_eq!(world.traversal_cost(&r, 0.001, 1.0), 0);
+        assert_eq!(heat(0, 3), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(heat(3, 3), Vec3::new(1.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,281 @@
+//! Minimal OpenEXR writer: single-part scanline images, half or float
+//! channels, stored uncompressed or with ZIP compression.
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelType {
+    Half,
+    Float,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// zlib over blocks of 16 scanlines.
+    Zip,
+}
+
+/// One layer of the image: `data` holds `channels.len()` floats per pixel,
+/// interleaved, rows from the top. Channels are written as `name.channel`,
+/// or just `channel` for the unnamed layer, each as `pixel_type`.
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub channels: &'a [&'a str],
+    pub data: &'a [f32],
+    pub pixel_type: PixelType,
+}
+
+/// Encodes the layers as an OpenEXR file.
+pub fn encode(width: u32, height: u32, layers: &[Layer], compression: Compression) -> Vec<u8> {
+    // Readers expect the channel list sorted by name, and pixel data follows
+    // the same order
+    let mut channels: Vec<(String, &Layer, usize)> = Vec::new();
+    for layer in layers {
+        for (i, channel) in layer.channels.iter().enumerate() {
+            let name = if layer.name.is_empty() {
+                channel.to_string()
+            } else {
+                format!("{}.{}", layer.name, channel)
+            };
+            channels.push((name, layer, i));
+        }
+    }
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut chlist = Vec::new();
+    for (name, layer, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        let code: i32 = match layer.pixel_type {
+            PixelType::Half => 1,
+            PixelType::Float => 2,
+        };
+        chlist.extend_from_slice(&code.to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    attribute(&mut header, "channels", "chlist", &chlist);
+    let code = match compression {
+        Compression::None => 0,
+        Compression::Zip => 3,
+    };
+    attribute(&mut header, "compression", "compression", &[code]);
+    let mut window = Vec::new();
+    for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let lines_per_block = match compression {
+        Compression::None => 1,
+        Compression::Zip => 16,
+    };
+    let mut chunks = Vec::new();
+    let mut y = 0;
+    while y < height {
+        let lines = lines_per_block.min(height - y);
+        let mut raw = Vec::new();
+        for row in y..y + lines {
+            for (_, layer, i) in &channels {
+                let stride = layer.channels.len();
+                for x in 0..width {
+                    let v = layer.data[(row * width + x) as usize * stride + i];
+                    match layer.pixel_type {
+                        PixelType::Half => raw.extend_from_slice(&f32_to_f16(v).to_le_bytes()),
+                        PixelType::Float => raw.extend_from_slice(&v.to_le_bytes()),
+                    }
+                }
+            }
+        }
+        let data = match compression {
+            Compression::None => raw,
+            Compression::Zip => zip(raw),
+        };
+        let mut chunk = Vec::with_capacity(data.len() + 8);
+        chunk.extend_from_slice(&(y as i32).to_le_bytes());
+        chunk.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        chunk.extend_from_slice(&data);
+        chunks.push(chunk);
+        y += lines;
+    }
+
+    let mut out = header;
+    let mut offset = (out.len() + 8 * chunks.len()) as u64;
+    for chunk in &chunks {
+        out.extend_from_slice(&offset.to_le_bytes());
+        offset += chunk.len() as u64;
+    }
+    for chunk in chunks {
+        out.extend_from_slice(&chunk);
+    }
+    out
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// OpenEXR's ZIP block: bytes split into even and odd halves, delta coded,
+/// then zlib compressed. A block that doesn't shrink is stored as is, which
+/// readers recognise by its size.
+fn zip(raw: Vec<u8>) -> Vec<u8> {
+    let half = raw.len().div_ceil(2);
+    let mut tmp = vec![0; raw.len()];
+    for (i, b) in raw.iter().enumerate() {
+        let j = if i % 2 == 0 { i / 2 } else { half + i / 2 };
+        tmp[j] = *b;
+    }
+    for i in (1..tmp.len()).rev() {
+        tmp[i] = tmp[i].wrapping_sub(tmp[i - 1]).wrapping_add(128);
+    }
+    let compressed = compress_to_vec_zlib(&tmp, 6);
+    if compressed.len() < raw.len() {
+        compressed
+    } else {
+        raw
+    }
+}
+
+/// Nearest IEEE half-precision value, ties to even. Values too large become
+/// infinity.
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (value, shift) = if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // Subnormal: the implicit leading one moves into the mantissa
+        (mantissa | 0x80_0000, (14 - e) as u32)
+    } else {
+        (((e as u32) << 23) | mantissa, 13)
+    };
+    let truncated = value >> shift;
+    let rest = value & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let rounded = if rest > halfway || (rest == halfway && truncated & 1 == 1) {
+        truncated + 1
+    } else {
+        truncated
+    };
+    sign | rounded as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, f32_to_f16, Compression, Layer, PixelType};
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    #[test]
+    fn half_conversion() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(0.0), 0);
+    }
+
+    #[test]
+    fn zip_block_round_trips() {
+        let (w, h) = (3, 20);
+        let beauty: Vec<f32> = (0..w * h * 3).map(|i| (i % 7) as f32).collect();
+        let depth: Vec<f32> = (0..w * h).map(|i| i as f32).collect();
+        let layers = [
+            Layer { name: "", channels: &["R", "G", "B"], data: &beauty, pixel_type: PixelType::Float },
+            Layer { name: "depth", channels: &["Z"], data: &depth, pixel_type: PixelType::Float },
+        ];
+        let plain = encode(w, h, &layers, Compression::None);
+        let zipped = encode(w, h, &layers, Compression::Zip);
+        assert!(zipped.len() < plain.len());
+
+        // The first ZIP chunk undone by hand matches the first 16
+        // uncompressed scanlines
+        let first = chunks(&zipped, 2)[0];
+        let mut tmp = decompress_to_vec_zlib(first).unwrap();
+        for i in 1..tmp.len() {
+            tmp[i] = tmp[i].wrapping_add(tmp[i - 1]).wrapping_sub(128);
+        }
+        let half = tmp.len().div_ceil(2);
+        let raw: Vec<u8> = (0..tmp.len())
+            .map(|i| if i % 2 == 0 { tmp[i / 2] } else { tmp[half + i / 2] })
+            .collect();
+
+        let expected: Vec<u8> = chunks(&plain, h as usize)[..16].concat();
+        assert_eq!(raw, expected);
+        // Channels are sorted by name, so a scanline starts with B
+        assert_eq!(&expected[..4], &2.0f32.to_le_bytes());
+        assert_eq!(&expected[4..8], &5.0f32.to_le_bytes());
+    }
+
+    #[test]
+    fn pixel_types_are_per_channel() {
+        let beauty = [0.5, 0.25, 1.0];
+        let id = [4097.0];
+        let layers = [
+            Layer { name: "", channels: &["R", "G", "B"], data: &beauty, pixel_type: PixelType::Half },
+            Layer { name: "object_id", channels: &["id"], data: &id, pixel_type: PixelType::Float },
+        ];
+        let file = encode(1, 1, &layers, Compression::None);
+        let chlist = file.windows(7).position(|w| w == b"chlist\0").unwrap() + 11;
+        // Each entry is the name, then the type code
+        assert_eq!(&file[chlist..chlist + 6], &[b'B', 0, 1, 0, 0, 0]);
+        assert_eq!(&file[chlist + 54..chlist + 67], b"object_id.id\0");
+        assert_eq!(&file[chlist + 67..chlist + 71], &2i32.to_le_bytes());
+        // Half B, G, R, then the ID exactly, which a half would round to 4096
+        let pixels = chunks(&file, 1)[0];
+        assert_eq!(pixels.len(), 3 * 2 + 4);
+        assert_eq!(&pixels[6..], &4097.0f32.to_le_bytes());
+        assert_eq!(f32_to_f16(4097.0), f32_to_f16(4096.0));
+    }
+
+    /// Pixel data of each chunk, found by skipping the header attributes
+    /// and following the offset table.
+    fn chunks(file: &[u8], count: usize) -> Vec<&[u8]> {
+        let read_i32 = |at: usize| i32::from_le_bytes([file[at], file[at + 1], file[at + 2], file[at + 3]]) as usize;
+        let mut at = 8;
+        loop {
+            let name = file[at..].iter().position(|b| *b == 0).unwrap();
+            at += name + 1;
+            if name == 0 {
+                break;
+            }
+            at += file[at..].iter().position(|b| *b == 0).unwrap() + 1;
+            at += 4 + read_i32(at);
+        }
+        (0..count)
+            .map(|i| {
+                let offset = read_i32(at + 8 * i);
+                &file[offset + 8..offset + 8 + read_i32(offset + 4)]
+            })
+            .collect()
+    }
+}
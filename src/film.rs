@@ -0,0 +1,385 @@
+use crate::filter::Filter;
+use crate::vec3::Vec3;
+
+/// HDR accumulation buffer. Each pixel keeps RGBA floats: the running sum of
+/// the filter-weighted linear radiance of the samples reaching it in RGB and
+/// the sum of their weights as A, so more samples can be added at any time
+/// and nothing is clipped until the image is displayed. With a box filter
+/// half a pixel wide the weights are sample counts. Rows are stored top to
+/// bottom.
+pub struct Film {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+    /// Samples taken in each pixel, wherever their weight went.
+    counts: Vec<f32>,
+    /// Sum of `counts`.
+    total: f32,
+    /// RGB sums of the light splatted straight onto each pixel, which
+    /// belongs to the whole image's samples rather than the pixel's own.
+    light: Vec<f32>,
+}
+
+/// Light reaching film position `(u, v)`, each in [0, 1] from the bottom
+/// left, from a path traced out of a light instead of through the pixel.
+/// A splat can land anywhere in the picture, whichever pixel's sample made
+/// the path.
+pub struct LightSplat<V> {
+    pub u: f32,
+    pub v: f32,
+    pub value: V,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width,
+            height,
+            data: vec![0.0; (width * height * 4) as usize],
+            counts: vec![0.0; (width * height) as usize],
+            total: 0.0,
+            light: vec![0.0; (width * height * 3) as usize],
+        }
+    }
+
+    /// Adds the weighted samples in `splats`. Rows of the tile outside the
+    /// film are dropped. Sample counts are recorded with `count_samples`.
+    pub fn add_splats(&mut self, splats: &Splats) {
+        for splat in &splats.light {
+            let x = ((splat.u * self.width as f32) as u32).min(self.width - 1);
+            let y = ((splat.v * self.height as f32) as u32).min(self.height - 1);
+            if let Some(i) = self.index(x, y) {
+                let i = i / 4 * 3;
+                self.light[i] += splat.value.r();
+                self.light[i + 1] += splat.value.g();
+                self.light[i + 2] += splat.value.b();
+            }
+        }
+        let stride = (self.width * 4) as usize;
+        for row in 0..splats.rows {
+            let y = splats.bottom + row as i64;
+            if y < 0 || y >= i64::from(self.height) {
+                continue;
+            }
+            let start = ((self.height - 1 - y as u32) * self.width * 4) as usize;
+            let from = row as usize * stride;
+            for (v, s) in self.data[start..start + stride].iter_mut().zip(&splats.data[from..from + stride]) {
+                *v += s;
+            }
+        }
+    }
+
+    /// Records that `count` more samples were taken in pixel `(x, y)`, with
+    /// `y` counting up from the bottom like `Scene::image_row`.
+    pub fn count_samples(&mut self, x: u32, y: u32, count: f32) {
+        if let Some(i) = self.index(x, y) {
+            self.counts[i / 4] += count;
+            self.total += count;
+        }
+    }
+
+    /// Everything accumulated so far, eight floats per pixel with rows from
+    /// the top: the RGB sum and weight as in the film, the samples taken in
+    /// the pixel, then the RGB sum of light splatted onto it.
+    pub fn accumulation(&self) -> Vec<f32> {
+        self.data
+            .chunks(4)
+            .zip(&self.counts)
+            .zip(self.light.chunks(3))
+            .flat_map(|((p, n), l)| vec![p[0], p[1], p[2], p[3], *n, l[0], l[1], l[2]])
+            .collect()
+    }
+
+    /// Adds another film's `accumulation` to this one's. Films rendering
+    /// different rows of one image each hold the weight their samples
+    /// spilled into rows the others rendered, and the light their paths
+    /// splatted anywhere, so their sum is the filtered image, with every
+    /// row's sample counts.
+    pub fn add_accumulation(&mut self, accumulation: &[f32]) {
+        let pixels = self.data.chunks_mut(4).zip(self.counts.iter_mut()).zip(self.light.chunks_mut(3));
+        for (((p, n), l), o) in pixels.zip(accumulation.chunks(8)) {
+            if o.len() < 8 {
+                break;
+            }
+            for (v, a) in p.iter_mut().zip(o) {
+                *v += a;
+            }
+            *n += o[4];
+            self.total += o[4];
+            for (v, a) in l.iter_mut().zip(&o[5..]) {
+                *v += a;
+            }
+        }
+    }
+
+    /// Samples taken in pixel `(x, y)`.
+    pub fn samples(&self, x: u32, y: u32) -> f32 {
+        self.index(x, y).map_or(0.0, |i| self.counts[i / 4])
+    }
+
+    /// Samples taken in each pixel, rows from the top.
+    pub fn sample_counts(&self) -> &[f32] {
+        &self.counts
+    }
+
+    /// Mean radiance of pixel `(x, y)`, black before any samples.
+    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
+        match self.index(x, y) {
+            Some(i) => {
+                let m = self.mean_at(i / 4);
+                Vec3::new(m[0], m[1], m[2])
+            }
+            None => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Mean radiance of every pixel, three floats each, rows from the top.
+    pub fn mean(&self) -> Vec<f32> {
+        (0..self.counts.len()).flat_map(|i| self.mean_at(i).to_vec()).collect()
+    }
+
+    /// Mean radiance of the `i`th pixel from the top left. Each sample of
+    /// the image made one light path, so the light splatted onto a pixel is
+    /// shared out over all of them.
+    fn mean_at(&self, i: usize) -> [f32; 3] {
+        let p = &self.data[i * 4..i * 4 + 4];
+        let n = if p[3] > 0.0 { p[3] } else { 1.0 };
+        let scale = if self.total > 0.0 {
+            self.counts.len() as f32 / self.total
+        } else {
+            0.0
+        };
+        let l = &self.light[i * 3..i * 3 + 3];
+        [p[0] / n + l[0] * scale, p[1] / n + l[1] * scale, p[2] / n + l[2] * scale]
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((((self.height - 1 - y) * self.width + x) * 4) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+/// Filter-weighted samples from one row of pixels, spread over the rows
+/// around it that the filter reaches, waiting to be added to a `Film`. Each
+/// row keeps RGB sums and weights like the film, with rows from the bottom.
+pub struct Splats {
+    width: u32,
+    /// Film row of the tile's first row, counting up from the bottom. It is
+    /// below zero for tiles hanging off the bottom of the image.
+    bottom: i64,
+    rows: u32,
+    filter: Filter,
+    radius: f32,
+    data: Vec<f32>,
+    light: Vec<LightSplat<Vec3>>,
+}
+
+impl Splats {
+    /// An empty tile for samples taken in row `y` of a film `width` pixels
+    /// wide, reconstructed with `filter` out to `radius` pixels.
+    /// Radii under half a pixel would lose samples landing near pixel
+    /// corners, so they are widened to half a pixel.
+    pub fn new(width: u32, y: u32, filter: Filter, radius: f32) -> Splats {
+        let radius = radius.max(0.5);
+        let reach = Filter::reach(radius);
+        let rows = 2 * reach + 1;
+        Splats {
+            width,
+            bottom: i64::from(y) - i64::from(reach),
+            rows,
+            filter,
+            radius,
+            data: vec![0.0; (width * rows * 4) as usize],
+            light: Vec::new(),
+        }
+    }
+
+    /// Keeps light splatted by the row's samples for the film, wherever in
+    /// the picture it landed.
+    pub fn add_light(&mut self, splat: LightSplat<Vec3>) {
+        self.light.push(splat);
+    }
+
+    /// Adds a sample of `color` that landed at film position `(px, py)`, in
+    /// pixels from the bottom left corner, to every pixel within the
+    /// filter's radius. Pixels whose centre is exactly the radius away only
+    /// count on one side, so a half pixel box gives each sample to exactly
+    /// one pixel.
+    pub fn add(&mut self, px: f32, py: f32, color: Vec3) {
+        let (x0, x1) = self.span(px);
+        let (y0, y1) = self.span(py);
+        let y0 = y0.max(self.bottom);
+        let y1 = y1.min(self.bottom + i64::from(self.rows) - 1);
+        let x0 = x0.max(0);
+        let x1 = x1.min(i64::from(self.width) - 1);
+        for y in y0..=y1 {
+            let wy = self.filter.evaluate(y as f32 + 0.5 - py, self.radius);
+            if wy == 0.0 {
+                continue;
+            }
+            for x in x0..=x1 {
+                let w = wy * self.filter.evaluate(x as f32 + 0.5 - px, self.radius);
+                let i = (((y - self.bottom) * i64::from(self.width) + x) * 4) as usize;
+                self.data[i] += w * color.r();
+                self.data[i + 1] += w * color.g();
+                self.data[i + 2] += w * color.b();
+                self.data[i + 3] += w;
+            }
+        }
+    }
+
+    /// Adds `weight` samples summing to `sum` to pixel `x` of the tile's
+    /// own row, without filtering.
+    pub fn add_pixel(&mut self, x: u32, sum: Vec3, weight: f32) {
+        let i = ((Filter::reach(self.radius) * self.width + x) * 4) as usize;
+        self.data[i] += sum.r();
+        self.data[i + 1] += sum.g();
+        self.data[i + 2] += sum.b();
+        self.data[i + 3] += weight;
+    }
+
+    /// First and last pixel whose centre lies within the radius of `p`,
+    /// including the far edge but not the near one.
+    fn span(&self, p: f32) -> (i64, i64) {
+        (
+            (p - 0.5 - self.radius).floor() as i64 + 1,
+            (p - 0.5 + self.radius).floor() as i64,
+        )
+    }
+}
+
+/// Running mean and variance of one pixel's sample luminance, updated by
+/// Welford's method, for deciding when the pixel has converged.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Welford {
+    n: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl Welford {
+    pub fn add(&mut self, x: f32) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.n
+    }
+
+    /// Unbiased sample variance, zero until there are two samples.
+    pub fn variance(&self) -> f32 {
+        if self.n > 1 {
+            (self.m2 / (self.n - 1) as f32).max(0.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated standard error of the mean, relative to the mean. Very dark
+    /// pixels are measured against a floor so they don't soak up samples
+    /// chasing noise nobody can see.
+    pub fn relative_error(&self) -> f32 {
+        if self.n == 0 {
+            return f32::INFINITY;
+        }
+        (self.variance() / self.n as f32).sqrt() / self.mean.max(0.01)
+    }
+}
+
+/// Last display step: quantises values already encoded for display to
+/// opaque 8-bit RGBA, clamping them to [0, 1].
+pub fn quantize(col: Vec3) -> [u8; 4] {
+    // Spectral samples outside the sRGB gamut come out negative
+    let channel = |c: f32| (255.99 * c.clamp(0.0, 1.0)) as u8;
+    [channel(col.r()), channel(col.g()), channel(col.b()), 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quantize, Film, LightSplat, Splats, Welford};
+    use crate::filter::Filter;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn accumulates_without_clipping() {
+        let mut film = Film::new(2, 2);
+        let mut splats = Splats::new(2, 0, Filter::Box, 0.5);
+        splats.add(1.5, 0.5, Vec3::new(4.0, 0.0, 1.0));
+        splats.add_pixel(1, Vec3::new(2.0, 2.0, 1.0), 3.0);
+        film.add_splats(&splats);
+        film.count_samples(1, 0, 4.0);
+        assert_eq!(film.samples(1, 0), 4.0);
+        assert_eq!(film.pixel(1, 0), Vec3::new(1.5, 0.5, 0.5));
+        assert_eq!(&film.accumulation()[24..], &[6.0, 2.0, 2.0, 4.0, 4.0, 0.0, 0.0, 0.0]);
+        assert_eq!(film.pixel(0, 0), Vec3::new(0.0, 0.0, 0.0));
+        assert_eq!(quantize(film.pixel(1, 0)), [255, 127, 127, 255]);
+
+        // Light splats are shared between all of the image's samples
+        let mut splats = Splats::new(2, 1, Filter::Box, 0.5);
+        splats.add_light(LightSplat { u: 0.2, v: 0.1, value: Vec3::new(0.0, 8.0, 0.0) });
+        film.add_splats(&splats);
+        assert_eq!(film.pixel(0, 0), Vec3::new(0.0, 8.0, 0.0));
+        film.count_samples(0, 1, 4.0);
+        assert_eq!(film.pixel(0, 0), Vec3::new(0.0, 4.0, 0.0));
+        assert_eq!(film.pixel(1, 0), Vec3::new(1.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn splats_reach_neighbouring_rows() {
+        // Half a pixel box keeps every sample in its own pixel, even on
+        // the pixel's edge
+        let mut film = Film::new(2, 2);
+        let mut splats = Splats::new(2, 1, Filter::Box, 0.5);
+        splats.add(0.0, 1.0, Vec3::new(1.0, 0.0, 0.0));
+        splats.add(1.99, 1.5, Vec3::new(0.0, 3.0, 0.0));
+        film.add_splats(&splats);
+        let acc = film.accumulation();
+        assert_eq!(&acc[..5], &[1.0, 0.0, 0.0, 1.0, 0.0]);
+        assert_eq!(&acc[8..13], &[0.0, 3.0, 0.0, 1.0, 0.0]);
+        assert_eq!(&acc[16..], &[0.0; 16]);
+
+        // Rows rendered separately, even by different films, blend across
+        // their boundaries into a constant image
+        let (width, height) = (5, 4);
+        let mut films = [Film::new(width, height), Film::new(width, height)];
+        for y in 0..height {
+            let mut splats = Splats::new(width, y, Filter::Mitchell, 2.0);
+            for x in 0..width {
+                for (i, j) in [(0.1, 0.3), (0.6, 0.8), (0.35, 0.55), (0.9, 0.05)].iter() {
+                    splats.add(x as f32 + i, y as f32 + j, Vec3::new(2.0, 2.0, 2.0));
+                }
+            }
+            films[(y % 2) as usize].add_splats(&splats);
+            films[(y % 2) as usize].count_samples(0, y, 4.0);
+        }
+        let other = films[1].accumulation();
+        films[0].add_accumulation(&other);
+        for y in 0..height {
+            for x in 0..width {
+                assert!((films[0].pixel(x, y).g() - 2.0).abs() < 1e-4);
+            }
+        }
+        assert_eq!(films[0].samples(0, 0), 4.0);
+        assert_eq!(films[0].samples(0, 1), 4.0);
+    }
+
+    #[test]
+    fn welford_matches_two_pass_variance() {
+        let xs = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let mut w = Welford::default();
+        for x in xs.iter() {
+            w.add(*x);
+        }
+        assert_eq!(w.count(), 8);
+        assert!((w.mean - 5.0).abs() < 1e-6);
+        assert!((w.variance() - 32.0 / 7.0).abs() < 1e-5);
+        assert!((w.relative_error() - (32.0f32 / 7.0 / 8.0).sqrt() / 5.0).abs() < 1e-5);
+        assert_eq!(Welford::default().relative_error(), f32::INFINITY);
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use std::f32::consts::PI;
+
+/// Pixel reconstruction filter: how much a sample counts towards a pixel
+/// given how far it landed from the pixel's centre. Filters are separable,
+/// so a sample's weight is the product of the horizontal and vertical ones.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    /// Every sample within the radius counts the same. With a radius of
+    /// half a pixel each sample only counts towards its own pixel.
+    Box,
+    /// Weight falls off linearly to zero at the radius.
+    Tent,
+    /// Gaussian with a standard deviation of a third of the radius, shifted
+    /// down to reach zero at the radius.
+    Gaussian,
+    /// Mitchell-Netravali cubic with B = C = 1/3, stretched over the radius.
+    /// Its small negative lobes sharpen a little.
+    Mitchell,
+    /// Lanczos windowed sinc, with as many lobes as the radius has pixels.
+    Lanczos,
+}
+
+impl Filter {
+    /// Weight of a sample `x` pixels from a pixel centre along one axis,
+    /// for a filter reaching `radius` pixels, which must be positive. Zero
+    /// beyond the radius.
+    pub fn evaluate(&self, x: f32, radius: f32) -> f32 {
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - x / radius,
+            Filter::Gaussian => {
+                let sigma = radius / 3.0;
+                let g = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (g(x) - g(radius)).max(0.0)
+            }
+            Filter::Mitchell => mitchell(2.0 * x / radius, 1.0 / 3.0, 1.0 / 3.0),
+            Filter::Lanczos => sinc(x) * sinc(x / radius),
+        }
+    }
+
+    /// Rows on either side of a sample's own row that its weight can reach.
+    pub fn reach(radius: f32) -> u32 {
+        (radius - 0.5).ceil().max(0.0) as u32
+    }
+}
+
+/// Mitchell-Netravali cubic over `[0, 2]`.
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+
+    #[test]
+    fn filters_peak_at_centre_and_vanish_at_radius() {
+        let all = [Filter::Box, Filter::Tent, Filter::Gaussian, Filter::Mitchell, Filter::Lanczos];
+        for filter in all.iter() {
+            let radius = 2.0;
+            let centre = filter.evaluate(0.0, radius);
+            assert!(centre > 0.0);
+            for i in 1..40 {
+                let x = i as f32 * 0.05;
+                assert!(filter.evaluate(x, radius) <= centre, "{:?} at {}", filter, x);
+                assert_eq!(filter.evaluate(x, radius), filter.evaluate(-x, radius));
+            }
+            assert_eq!(filter.evaluate(2.01, radius), 0.0);
+            if *filter != Filter::Box {
+                assert!(filter.evaluate(radius, radius).abs() < 1e-4, "{:?}", filter);
+            }
+        }
+        assert!(Filter::Mitchell.evaluate(1.5, 2.0) < 0.0);
+        assert_eq!(Filter::reach(0.5), 0);
+        assert_eq!(Filter::reach(1.5), 1);
+        assert_eq!(Filter::reach(2.0), 2);
+    }
+}
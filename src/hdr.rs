@@ -0,0 +1,46 @@
+//! Radiance `.hdr` writer: shared-exponent RGBE pixels, stored flat.
+
+/// Encodes linear RGB, three floats per pixel with rows from the top, as a
+/// Radiance picture.
+pub fn encode(width: u32, height: u32, rgb: &[f32]) -> Vec<u8> {
+    let header = format!(
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    );
+    let mut out = header.into_bytes();
+    out.reserve((width * height * 4) as usize);
+    for p in rgb.chunks(3).take((width * height) as usize) {
+        out.extend_from_slice(&rgbe(p[0], p[1], p[2]));
+    }
+    out
+}
+
+/// Mantissas of all three channels share the exponent of the largest, so
+/// negatives clamp to zero and anything too dim to show becomes black.
+fn rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let v = r.max(g).max(b);
+    if v.is_nan() || v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // Keeps the exponent byte in range
+    let v = v.min(1e38);
+    // v = m * 2^e with m in [0.5, 1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(e);
+    let channel = |c: f32| (c.min(v) * scale).min(255.0) as u8;
+    [channel(r), channel(g), channel(b), (e + 128) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+
+    #[test]
+    fn header_and_pixels() {
+        let bytes = encode(2, 1, &[1.0, 0.5, 0.25, 0.0, -1.0, 0.0]);
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        assert_eq!(&bytes[header.len()..], &[128, 64, 32, 129, 0, 0, 0, 0]);
+    }
+}
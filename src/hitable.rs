@@ -1,17 +1,97 @@
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::Vec3;
 use serde::{Serialize, Deserialize};
 
 pub trait Hitable {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+
+    /// Solid-angle density of `random` picking direction `v` from `o`.
+    fn pdf_value(&self, _o: &Vec3, _v: &Vec3) -> f32 {
+        0.0
+    }
+
+    /// Direction from `o` towards a random point on the object, for sampling
+    /// it as a light.
+    fn random(&self, _o: &Vec3, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    /// Uniformly chosen point on the surface and its area density, for
+    /// starting light paths.
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(HitRecord<'_>, f32)> {
+        None
+    }
+
+    /// Area density of `sample_surface` picking `p`, zero off the surface.
+    fn surface_pdf(&self, _p: &Vec3) -> f32 {
+        0.0
+    }
+
+    /// Centre and radius of a sphere enclosing the object.
+    fn bounding_sphere(&self) -> Option<(Vec3, f32)> {
+        None
+    }
+
+    /// Material of a single primitive, for telling materials apart in debug
+    /// views.
+    fn primitive_material(&self) -> Option<&Material> {
+        None
+    }
+
+    /// Primitives whose bounds the ray passes through between `t_min` and
+    /// `t_max`, each needing a full intersection test.
+    fn traversal_cost(&self, r: &Ray, t_min: f32, t_max: f32) -> u32 {
+        let (center, radius) = match self.bounding_sphere() {
+            Some(bounds) => bounds,
+            None => return 1,
+        };
+        let d = r.direction();
+        let t = ((center - r.origin()).dot(&d) / d.dot(&d)).max(t_min).min(t_max);
+        if (r.point_at_parameter(t) - center).squared_length() <= radius * radius {
+            1
+        } else {
+            0
+        }
+    }
 }
 
-pub struct HitRecord {
+pub struct HitRecord<'a> {
     pub t: f32,
     pub p: Vec3,
+    /// Shading normal, which normal maps may tilt away from the true surface.
     pub normal: Vec3,
-    pub material: Material,
+    pub geometric_normal: Vec3,
+    /// Partial derivatives of the surface position along the texture
+    /// coordinates, giving the tangent frame.
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub u: f32,
+    pub v: f32,
+    pub material: &'a Material,
+    /// Hero wavelength in nanometres when rendering spectrally.
+    pub wavelength: Option<f32>,
+}
+
+#[cfg(test)]
+impl<'a> HitRecord<'a> {
+    /// Hit at the origin of a surface facing +z, with `u` running along x
+    /// and `v` along y.
+    pub fn facing_z(material: &'a Material) -> HitRecord<'a> {
+        HitRecord {
+            t: 1.0,
+            p: Vec3::new(0.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 1.0, 0.0),
+            u: 0.0,
+            v: 0.0,
+            material,
+            wavelength: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,20 +99,51 @@ pub struct HitList<T: Hitable> {
     pub list: Vec<T>,
 }
 
-impl<T: Hitable> Hitable for HitList<T> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+impl<T: Hitable> HitList<T> {
+    /// Closest hit and the index of the item it belongs to.
+    pub fn hit_index(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(usize, HitRecord<'_>)> {
         let mut closest_so_far: f32 = t_max;
 
         let mut rec = None;
-        for item in &self.list {
-            match item.hit(r, t_min, closest_so_far) {
-                Some(x) => {
-                    closest_so_far = x.t;
-                    rec = Some(x);
-                }
-                None => (),
+        for (i, item) in self.list.iter().enumerate() {
+            if let Some(x) = item.hit(r, t_min, closest_so_far) {
+                closest_so_far = x.t;
+                rec = Some((i, x));
             }
         }
         rec
     }
 }
+
+impl<T: Hitable> Hitable for HitList<T> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.hit_index(r, t_min, t_max).map(|(_, rec)| rec)
+    }
+
+    fn traversal_cost(&self, r: &Ray, t_min: f32, t_max: f32) -> u32 {
+        // With no acceleration structure the list visits every item, and
+        // each one the ray could hit costs a full test
+        self.list.iter().map(|item| item.traversal_cost(r, t_min, t_max)).sum()
+    }
+
+    fn bounding_sphere(&self) -> Option<(Vec3, f32)> {
+        let mut bounds: Option<(Vec3, f32)> = None;
+        for (c, r) in self.list.iter().filter_map(|item| item.bounding_sphere()) {
+            bounds = Some(match bounds {
+                None => (c, r),
+                Some((center, radius)) => {
+                    let d = (c - center).length();
+                    if d + r <= radius {
+                        (center, radius)
+                    } else if d + radius <= r {
+                        (c, r)
+                    } else {
+                        let merged = (d + radius + r) / 2.0;
+                        (center + (c - center) * ((merged - radius) / d), merged)
+                    }
+                }
+            });
+        }
+        bounds
+    }
+}
@@ -0,0 +1,597 @@
+use crate::bdpt::Bdpt;
+use crate::camera::Camera;
+use crate::debug::DebugIntegrator;
+use crate::film::LightSplat;
+use crate::hitable::{HitList, HitRecord, Hitable};
+use crate::material::{Bsdf, Subsurface};
+use crate::mlt::Mlt;
+use crate::onb::Onb;
+use crate::photon::PhotonMapper;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::settings::{IntegratorKind, RenderSettings};
+use crate::spectrum::{ColorMode, Spectrum};
+use crate::vec3::Vec3;
+
+/// Integrator picked by name in the render settings.
+pub enum Integrator {
+    Path(PathTracer),
+    Bdpt(Bdpt),
+    Photon(PhotonMapper),
+    Mlt(Mlt),
+    Debug(DebugIntegrator),
+}
+
+impl Integrator {
+    pub fn new(settings: &RenderSettings, camera: &Camera) -> Integrator {
+        match settings.integrator {
+            IntegratorKind::Path => Integrator::Path(PathTracer::new(settings)),
+            IntegratorKind::Bdpt => Integrator::Bdpt(Bdpt::new(settings, camera.clone())),
+            IntegratorKind::Photon => Integrator::Photon(PhotonMapper::new(settings, false)),
+            IntegratorKind::ProgressivePhoton => Integrator::Photon(PhotonMapper::new(settings, true)),
+            IntegratorKind::Mlt => Integrator::Mlt(Mlt::new(settings)),
+            _ => Integrator::Debug(DebugIntegrator::new(settings)),
+        }
+    }
+
+    /// True for the debug views, whose colours are shown without gamma.
+    pub fn is_diagnostic(&self) -> bool {
+        matches!(self, Integrator::Debug(_))
+    }
+
+    /// Work done once per scene before rendering, such as shooting photons.
+    /// `targets` are the specular objects and `focus` a sphere around what the
+    /// camera sees.
+    pub fn preprocess<T: Hitable>(
+        &mut self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+    ) {
+        match self {
+            Integrator::Photon(integrator) => integrator.preprocess(world, lights, targets, focus),
+            Integrator::Debug(integrator) => integrator.preprocess(world, focus),
+            _ => {}
+        }
+    }
+
+    /// Work done before each round of samples, `render_pass` or
+    /// `image_row`, such as shooting a fresh progressive photon pass. Takes
+    /// the same scene description as `preprocess`.
+    pub fn next_pass<T: Hitable>(
+        &mut self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+    ) {
+        if let Integrator::Photon(integrator) = self {
+            integrator.next_pass(world, lights, targets, focus);
+        }
+    }
+
+    /// Radiance arriving back along `r`. Integrators that also trace light
+    /// paths straight to the camera add what they bring to `splats`.
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<LightSplat<C::Value>>,
+    ) -> C::Value {
+        match self {
+            Integrator::Path(integrator) => integrator.li(r, world, lights, mode, sampler),
+            Integrator::Bdpt(integrator) => integrator.li(r, world, lights, mode, sampler, splats),
+            Integrator::Photon(integrator) => integrator.li(r, world, lights, mode, sampler),
+            Integrator::Mlt(integrator) => integrator.li(r, world, lights, mode, sampler),
+            Integrator::Debug(integrator) => integrator.li(r, world, mode, sampler),
+        }
+    }
+
+    /// Whether `li_paths` can split this integrator's radiance.
+    pub fn splits_paths(&self) -> bool {
+        matches!(self, Integrator::Path(_))
+    }
+
+    /// Radiance along `r` split by how it reached the camera, for integrators
+    /// that can tell; `None` for the rest.
+    pub fn li_paths<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightPaths<C::Value>> {
+        match self {
+            Integrator::Path(integrator) => Some(integrator.li_paths(r, world, lights, mode, sampler)),
+            _ => None,
+        }
+    }
+}
+
+/// Radiance arriving at the camera sorted by path: emitters and sky seen
+/// directly, light scattered once at the first surface (direct), and light
+/// scattered more than once (indirect). Diffuse or specular is decided by the
+/// material at the first surface.
+#[derive(Clone, Copy, Debug)]
+pub struct LightPaths<V> {
+    pub emission: V,
+    pub direct_diffuse: V,
+    pub indirect_diffuse: V,
+    pub direct_specular: V,
+    pub indirect_specular: V,
+}
+
+impl<V: Spectrum> LightPaths<V> {
+    pub fn new(zero: V) -> LightPaths<V> {
+        LightPaths {
+            emission: zero,
+            direct_diffuse: zero,
+            indirect_diffuse: zero,
+            direct_specular: zero,
+            indirect_specular: zero,
+        }
+    }
+
+    /// Adds light that arrived after `scatters` scattering events.
+    fn add(&mut self, scatters: u32, diffuse: bool, value: V) {
+        let bin = match (scatters, diffuse) {
+            (0, _) => &mut self.emission,
+            (1, true) => &mut self.direct_diffuse,
+            (1, false) => &mut self.direct_specular,
+            (_, true) => &mut self.indirect_diffuse,
+            (_, false) => &mut self.indirect_specular,
+        };
+        *bin += value;
+    }
+
+    pub fn total(&self) -> V {
+        let mut total = self.emission;
+        total += self.direct_diffuse;
+        total += self.indirect_diffuse;
+        total += self.direct_specular;
+        total += self.indirect_specular;
+        total
+    }
+
+    /// Scales down the direct light (emission included) and the indirect
+    /// light, each as a whole, wherever its `brightness` exceeds `direct` or
+    /// `indirect`. A limit of zero leaves that part alone.
+    pub fn clamp<F: Fn(&V) -> f32>(&mut self, direct: f32, indirect: f32, brightness: F) {
+        let mut direct_total = self.emission;
+        direct_total += self.direct_diffuse;
+        direct_total += self.direct_specular;
+        let mut indirect_total = self.indirect_diffuse;
+        indirect_total += self.indirect_specular;
+        let scale = |total: V, limit: f32| {
+            let max = brightness(&total);
+            if limit > 0.0 && max > limit {
+                limit / max
+            } else {
+                1.0
+            }
+        };
+        let d = scale(direct_total, direct);
+        let i = scale(indirect_total, indirect);
+        self.emission = self.emission * d;
+        self.direct_diffuse = self.direct_diffuse * d;
+        self.direct_specular = self.direct_specular * d;
+        self.indirect_diffuse = self.indirect_diffuse * i;
+        self.indirect_specular = self.indirect_specular * i;
+    }
+
+    pub fn map<W, F: Fn(&V) -> W>(&self, f: F) -> LightPaths<W> {
+        LightPaths {
+            emission: f(&self.emission),
+            direct_diffuse: f(&self.direct_diffuse),
+            indirect_diffuse: f(&self.indirect_diffuse),
+            direct_specular: f(&self.direct_specular),
+            indirect_specular: f(&self.indirect_specular),
+        }
+    }
+}
+
+/// Unidirectional path tracer. Each vertex samples a light and the BSDF and
+/// weights the two with the power heuristic; paths end at `max_depth` or
+/// earlier by Russian roulette.
+///
+/// For previews it can trade bias for less noise: each sample's direct and
+/// indirect light can be clamped, and specular bounces after a path's first
+/// can be regularised, blurred into a narrow cone of directions so caustic
+/// paths find lights by light sampling instead of by chance.
+pub struct PathTracer {
+    max_depth: u32,
+    rr_depth: u32,
+    clamp_direct: f32,
+    clamp_indirect: f32,
+    /// Cosine of the half-angle of the cone regularised bounces spread
+    /// over, when regularising.
+    regularize: Option<f32>,
+}
+
+impl PathTracer {
+    pub fn new(settings: &RenderSettings) -> PathTracer {
+        PathTracer {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+            clamp_direct: settings.clamp_direct,
+            clamp_indirect: settings.clamp_indirect,
+            regularize: if settings.regularize && settings.regularize_angle > 0.0 {
+                Some(settings.regularize_angle.min(90.0).to_radians().cos())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> C::Value {
+        self.li_paths(r, world, lights, mode, sampler).total()
+    }
+
+    pub fn li_paths<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> LightPaths<C::Value> {
+        let mut radiance = LightPaths::new(mode.constant(0.0));
+        let mut throughput = mode.constant(1.0);
+        let mut ray = r;
+        let mut medium: Option<&Subsurface> = None;
+        // Emitters seen from the camera or through a specular bounce can't
+        // have been light sampled, so they count in full
+        let mut specular_bounce = true;
+        let mut bsdf_pdf = 0.0;
+        let mut scatters = 0;
+        let mut diffuse = true;
+        let mut after_specular = false;
+
+        for depth in 0.. {
+            let mut x = match world.hit(&ray, 0.001, f32::MAX) {
+                Some(x) => x,
+                None => {
+                    radiance.add(scatters, diffuse, throughput * mode.rgb(background(&ray)));
+                    break;
+                }
+            };
+
+            if let Some(m) = medium {
+                let length = ray.direction().length();
+                let flight = m.sample_free_flight(x.t * length, sampler);
+                throughput = throughput * mode.rgb(flight.weight);
+                if flight.scattered {
+                    if depth >= self.max_depth {
+                        break;
+                    }
+                    let p = ray.point_at_parameter(flight.distance / length);
+                    ray = Ray::new(p, m.sample_phase(sampler));
+                    specular_bounce = true;
+                    scatters += 1;
+                    continue;
+                }
+            }
+
+            x.wavelength = mode.wavelength();
+            let (uvw, wo) = shading_frame(&mut x, &ray);
+
+            let emitted = x.material.emitted(&x);
+            if emitted.max_value() > 0.0 {
+                let weight = if specular_bounce {
+                    1.0
+                } else {
+                    let light_pdf = light_pdf(lights, &ray.origin(), &ray.direction());
+                    power_heuristic(bsdf_pdf, light_pdf)
+                };
+                radiance.add(scatters, diffuse, throughput * mode.rgb(emitted) * weight);
+            }
+
+            if depth >= self.max_depth {
+                break;
+            }
+
+            if scatters == 0 {
+                diffuse = x.material.is_diffuse();
+            }
+            if !lights.is_empty() {
+                let direct = sample_light(world, lights, &x, &uvw, &wo, mode, sampler);
+                radiance.add(scatters + 1, diffuse, throughput * direct);
+            }
+
+            let s = match x.material.sample(&x, &wo, sampler) {
+                Some(s) => s,
+                None => break,
+            };
+            let mut direction = uvw.to_world(&s.wi);
+            let mut wi = s.wi;
+            specular_bounce = s.specular;
+            bsdf_pdf = s.pdf;
+            if let (Some(cos_max), true) = (self.regularize, s.specular && after_specular) {
+                // A cone of directions has a density, so lights can be
+                // sampled through the bounce and weighted against hitting them
+                if !lights.is_empty() {
+                    let weight = mode.rgb(s.weight());
+                    let direct = sample_light_cone(world, lights, &x, &direction, cos_max, mode, sampler);
+                    radiance.add(scatters + 1, diffuse, throughput * weight * direct);
+                }
+                direction = sample_cone(&direction, cos_max, sampler);
+                wi = uvw.to_local(&direction);
+                specular_bounce = false;
+                bsdf_pdf = cone_pdf(cos_max);
+            }
+            if leaks(&x, &-ray.direction(), &direction, &wo, &wi) {
+                break;
+            }
+            if s.dispersive {
+                mode.terminate_secondary();
+            }
+            throughput = throughput * mode.rgb(s.weight());
+            after_specular |= s.specular;
+            scatters += 1;
+            medium = if direction.dot(&x.geometric_normal) < 0.0 {
+                x.material.medium()
+            } else {
+                None
+            };
+            ray = Ray::new(x.p, direction);
+
+            if depth >= self.rr_depth {
+                let survive = throughput.max_value().min(0.95);
+                if survive <= 0.0 || sampler.next_1d() >= survive {
+                    break;
+                }
+                throughput = throughput * (1.0 / survive);
+            }
+        }
+        // Limits are on the colour a sample adds to the image, which for
+        // sampled wavelengths is only known after dividing by their density
+        radiance.clamp(self.clamp_direct, self.clamp_indirect, |v| mode.brightness(v));
+        radiance
+    }
+}
+
+/// Next event estimation: one direction towards a randomly chosen light,
+/// weighted against the chance of the BSDF having sampled it.
+pub fn sample_light<T: Hitable, C: ColorMode>(
+    world: &HitList<T>,
+    lights: &[&T],
+    x: &HitRecord,
+    uvw: &Onb,
+    wo: &Vec3,
+    mode: &C,
+    sampler: &mut dyn Sampler,
+) -> C::Value {
+    let none = mode.constant(0.0);
+    let index = ((sampler.next_1d() * lights.len() as f32) as usize).min(lights.len() - 1);
+    let direction = lights[index].random(&x.p, sampler).unit();
+    let wi = uvw.to_local(&direction);
+    let f = x.material.eval(x, wo, &wi);
+    if f.max_value() <= 0.0 {
+        return none;
+    }
+    if leaks(x, &uvw.to_world(wo), &direction, wo, &wi) {
+        return none;
+    }
+    let shadow = Ray::new(x.p, direction);
+    let light_pdf = light_pdf(lights, &x.p, &direction);
+    if light_pdf <= 0.0 {
+        return none;
+    }
+    // Whatever the shadow ray hits first is what lights the point, so
+    // occluders and cut-outs are handled by the ordinary hit test
+    let emitted = match world.hit(&shadow, 0.001, f32::MAX) {
+        Some(light) => light.material.emitted(&light),
+        None => return none,
+    };
+    if emitted.max_value() <= 0.0 {
+        return none;
+    }
+    let weight = power_heuristic(light_pdf, x.material.pdf(x, wo, &wi));
+    mode.rgb(f * emitted) * (wi.z().abs() * weight / light_pdf)
+}
+
+/// Next event estimation through a specular bounce regularised into a cone
+/// of directions within `cos_max` of `axis`, all equally likely. The result
+/// is still to be multiplied by the bounce's own weight.
+fn sample_light_cone<T: Hitable, C: ColorMode>(
+    world: &HitList<T>,
+    lights: &[&T],
+    x: &HitRecord,
+    axis: &Vec3,
+    cos_max: f32,
+    mode: &C,
+    sampler: &mut dyn Sampler,
+) -> C::Value {
+    let none = mode.constant(0.0);
+    let index = ((sampler.next_1d() * lights.len() as f32) as usize).min(lights.len() - 1);
+    let direction = lights[index].random(&x.p, sampler).unit();
+    let ng = x.geometric_normal;
+    if direction.dot(axis) < cos_max || direction.dot(&ng) * axis.dot(&ng) <= 0.0 {
+        return none;
+    }
+    let light_pdf = light_pdf(lights, &x.p, &direction);
+    if light_pdf <= 0.0 {
+        return none;
+    }
+    let emitted = match world.hit(&Ray::new(x.p, direction), 0.001, f32::MAX) {
+        Some(light) => light.material.emitted(&light),
+        None => return none,
+    };
+    if emitted.max_value() <= 0.0 {
+        return none;
+    }
+    let pdf = cone_pdf(cos_max);
+    mode.rgb(emitted) * (pdf * power_heuristic(light_pdf, pdf) / light_pdf)
+}
+
+/// Uniform direction within `cos_max` of `axis`.
+fn sample_cone(axis: &Vec3, cos_max: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let z = 1.0 + r2 * (cos_max - 1.0);
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+    Onb::from_w(axis).to_world(&Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z))
+}
+
+fn cone_pdf(cos_max: f32) -> f32 {
+    1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_max))
+}
+
+/// Density of light sampling picking direction `v` from `o`: a light is
+/// chosen uniformly and then samples its own solid angle.
+pub fn light_pdf<T: Hitable>(lights: &[&T], o: &Vec3, v: &Vec3) -> f32 {
+    if lights.is_empty() {
+        return 0.0;
+    }
+    lights.iter().map(|l| l.pdf_value(o, v)).sum::<f32>() / lights.len() as f32
+}
+
+pub fn power_heuristic(f_pdf: f32, g_pdf: f32) -> f32 {
+    let f2 = f_pdf * f_pdf;
+    let g2 = g_pdf * g_pdf;
+    if f2 + g2 == 0.0 {
+        return 0.0;
+    }
+    f2 / (f2 + g2)
+}
+
+/// Local shading frame at a hit and the outgoing direction in it. A normal map
+/// can tilt the shading normal away from the viewer, which would leave black
+/// fringes, so such normals are bent back until the viewer just sees them.
+pub fn shading_frame(x: &mut HitRecord, r: &Ray) -> (Onb, Vec3) {
+    let wo = -r.direction().unit();
+    let mut normal = x.material.shading_normal(x);
+    let side = if wo.dot(&x.geometric_normal) < 0.0 { -1.0 } else { 1.0 };
+    let cosine = side * wo.dot(&normal);
+    if cosine <= 0.0 {
+        normal = (normal + (0.01 - cosine) * side * wo).unit();
+    }
+    x.normal = normal;
+    let uvw = Onb::from_w(&normal);
+    let wo = uvw.to_local(&wo);
+    (uvw, wo)
+}
+
+/// True when a sampled direction would reflect according to the shading
+/// normal but pass through the real surface, or the other way round. `toward`
+/// is the world-space direction back along the incoming ray.
+pub fn leaks(x: &HitRecord, toward: &Vec3, direction: &Vec3, wo: &Vec3, wi: &Vec3) -> bool {
+    let ng = x.geometric_normal;
+    let geometric_reflect = toward.dot(&ng) * direction.dot(&ng) > 0.0;
+    let shading_reflect = wo.z() * wi.z() > 0.0;
+    geometric_reflect != shading_reflect
+}
+
+pub fn background(r: &Ray) -> Vec3 {
+    let unit_direction = r.direction().unit();
+    let t: f32 = 0.5 * (unit_direction.y() + 1.0);
+    (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{power_heuristic, LightPaths, PathTracer};
+    use crate::hitable::HitList;
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Material};
+    use crate::ray::Ray;
+    use crate::sampler::{Independent, Sampler};
+    use crate::settings::RenderSettings;
+    use crate::spectrum::{Rgb, SampledWavelengths, Spectrum};
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn power_heuristic_weights_sum_to_one() {
+        let (a, b) = (0.3, 1.7);
+        assert!((power_heuristic(a, b) + power_heuristic(b, a) - 1.0).abs() < 1e-6);
+        assert_eq!(power_heuristic(1.0, 0.0), 1.0);
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn clamp_limits_direct_and_indirect_separately() {
+        let mut paths = LightPaths::new(Vec3::new(0.0, 0.0, 0.0));
+        paths.emission = Vec3::new(1.0, 0.0, 0.0);
+        paths.direct_diffuse = Vec3::new(1.0, 2.0, 0.0);
+        paths.indirect_specular = Vec3::new(0.0, 0.0, 40.0);
+        paths.indirect_diffuse = Vec3::new(10.0, 0.0, 0.0);
+        paths.clamp(0.0, 4.0, |v| v.max_value());
+        assert_eq!(paths.emission, Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(paths.direct_diffuse, Vec3::new(1.0, 2.0, 0.0));
+        assert_eq!(paths.indirect_specular, Vec3::new(0.0, 0.0, 4.0));
+        assert_eq!(paths.indirect_diffuse, Vec3::new(1.0, 0.0, 0.0));
+        paths.clamp(1.0, 0.0, |v| v.max_value());
+        assert_eq!(paths.emission, Vec3::new(0.5, 0.0, 0.0));
+        assert_eq!(paths.direct_diffuse, Vec3::new(0.5, 1.0, 0.0));
+    }
+
+    #[test]
+    fn spectral_samples_are_clamped_in_rgb() {
+        let light = Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, Material::DiffuseLight { mat: DiffuseLight::new(20.0, 10.0, 5.0) });
+        let world = HitList { list: vec![light] };
+        let lights = [&world.list[0]];
+        let path = PathTracer::new(&RenderSettings { clamp_direct: 0.5, ..RenderSettings::default() });
+        let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler = Independent::new();
+        for _ in 0..100 {
+            let mut lambda = SampledWavelengths::sample_uniform(sampler.next_1d());
+            let radiance = path.li(r, &world, &lights, &mut lambda, &mut sampler);
+            let brightest = lambda.to_rgb(&radiance).max_value();
+            assert!((brightest - 0.5).abs() < 1e-3, "{}", brightest);
+        }
+    }
+
+    #[test]
+    fn regularised_paths_converge_as_the_cone_narrows() {
+        // Light through a glass ball: leaving the ball is a path's second
+        // specular bounce, the first to be regularised
+        let mut world = HitList { list: Vec::new() };
+        world.list.push(Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, Material::Dielectric { mat: Dielectric::new(1.5) }));
+        world.list.push(Sphere::new(Vec3::new(0.0, 0.0, -8.0), 2.0, Material::DiffuseLight { mat: DiffuseLight::new(4.0, 4.0, 4.0) }));
+        world.list.push(Sphere::new(Vec3::new(0.0, -1003.0, 0.0), 1000.0, Material::Lambertian { mat: Lambertian::new(0.5, 0.5, 0.5) }));
+        let lights = [&world.list[1]];
+        let mut sampler = Independent::new();
+        let mut estimate = |direction: Vec3, angle: Option<f32>| {
+            let settings = RenderSettings {
+                regularize: angle.is_some(),
+                regularize_angle: angle.unwrap_or(0.0),
+                ..RenderSettings::default()
+            };
+            let path = PathTracer::new(&settings);
+            let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), direction);
+            let n = 100_000;
+            (0..n).map(|_| path.li(r, &world, &lights, &mut Rgb, &mut sampler).x()).sum::<f32>() / n as f32
+        };
+
+        // Seen off centre, a wide cone reaches past the light, and the bias
+        // goes away as it narrows
+        let off_centre = Vec3::new(0.0, -0.2, -1.0);
+        let reference = estimate(off_centre, None);
+        let error = |value: f32| value / reference - 1.0;
+        let wide = error(estimate(off_centre, Some(30.0)));
+        assert!(wide < -0.05, "{}", wide);
+        for angle in [3.0, 0.3].iter() {
+            let e = error(estimate(off_centre, Some(*angle)));
+            assert!(e.abs() < 0.015, "{} degrees: {}", angle, e);
+        }
+
+        // Through the centre the light fills even a wide cone, so only
+        // mistakes in the cone's density or its MIS weights would show
+        let centre = Vec3::new(0.0, 0.0, -1.0);
+        let reference = estimate(centre, None);
+        let e = estimate(centre, Some(25.0)) / reference - 1.0;
+        assert!(e.abs() < 0.015, "{}", e);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::vec3::Vec3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Anything stored in a `KdTree`.
+pub trait Point {
+    fn position(&self) -> Vec3;
+}
+
+/// Balanced kd-tree kept implicitly in one array: each range's median is the
+/// node splitting it, along the axis stored next to it.
+pub struct KdTree<T: Point> {
+    items: Vec<T>,
+    axes: Vec<u8>,
+}
+
+struct Neighbor {
+    distance_squared: f32,
+    index: usize,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance_squared == other.distance_squared
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_squared
+            .partial_cmp(&other.distance_squared)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl<T: Point> KdTree<T> {
+    pub fn new(mut items: Vec<T>) -> KdTree<T> {
+        let mut axes = vec![0; items.len()];
+        build(&mut items, &mut axes);
+        KdTree { items, axes }
+    }
+
+    /// Calls `visit` with every item within `sqrt(radius_squared)` of `p` and
+    /// its squared distance.
+    pub fn within<F: FnMut(&T, f32)>(&self, p: &Vec3, radius_squared: f32, mut visit: F) {
+        self.within_range(0, self.items.len(), p, radius_squared, &mut visit);
+    }
+
+    fn within_range<F: FnMut(&T, f32)>(&self, lo: usize, hi: usize, p: &Vec3, radius_squared: f32, visit: &mut F) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let item = &self.items[mid];
+        let distance_squared = (item.position() - *p).squared_length();
+        if distance_squared <= radius_squared {
+            visit(item, distance_squared);
+        }
+        let axis = self.axes[mid] as usize;
+        let delta = p[axis] - item.position()[axis];
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.within_range(near.0, near.1, p, radius_squared, visit);
+        if delta * delta <= radius_squared {
+            self.within_range(far.0, far.1, p, radius_squared, visit);
+        }
+    }
+
+    /// Up to `k` items closest to `p` and no further than
+    /// `sqrt(max_radius_squared)`, with the squared distance to the furthest.
+    pub fn nearest(&self, p: &Vec3, k: usize, max_radius_squared: f32) -> (Vec<&T>, f32) {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            self.nearest_range(0, self.items.len(), p, k, max_radius_squared, &mut heap);
+        }
+        let radius_squared = heap.peek().map_or(0.0, |n| n.distance_squared);
+        let items = heap.into_iter().map(|n| &self.items[n.index]).collect();
+        (items, radius_squared)
+    }
+
+    fn nearest_range(
+        &self,
+        lo: usize,
+        hi: usize,
+        p: &Vec3,
+        k: usize,
+        max_radius_squared: f32,
+        heap: &mut BinaryHeap<Neighbor>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let position = self.items[mid].position();
+        let axis = self.axes[mid] as usize;
+        let delta = p[axis] - position[axis];
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.nearest_range(near.0, near.1, p, k, max_radius_squared, heap);
+
+        let bound = |heap: &BinaryHeap<Neighbor>| {
+            if heap.len() < k {
+                max_radius_squared
+            } else {
+                heap.peek().map_or(max_radius_squared, |n| n.distance_squared)
+            }
+        };
+        let distance_squared = (position - *p).squared_length();
+        if distance_squared <= bound(heap) {
+            heap.push(Neighbor {
+                distance_squared,
+                index: mid,
+            });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        if delta * delta <= bound(heap) {
+            self.nearest_range(far.0, far.1, p, k, max_radius_squared, heap);
+        }
+    }
+}
+
+fn build<T: Point>(items: &mut [T], axes: &mut [u8]) {
+    if items.len() <= 1 {
+        return;
+    }
+    // Split along the axis the points are most spread out on
+    let mut min = items[0].position();
+    let mut max = min;
+    for item in items.iter() {
+        let p = item.position();
+        min = Vec3::new(min.x().min(p.x()), min.y().min(p.y()), min.z().min(p.z()));
+        max = Vec3::new(max.x().max(p.x()), max.y().max(p.y()), max.z().max(p.z()));
+    }
+    let extent = max - min;
+    let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() {
+        0
+    } else if extent.y() >= extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let mid = items.len() / 2;
+    items.select_nth_unstable_by(mid, |a, b| {
+        a.position()[axis]
+            .partial_cmp(&b.position()[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[mid] = axis as u8;
+    let (left, right) = items.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KdTree, Point, Vec3};
+    use rand::prelude::*;
+
+    impl Point for Vec3 {
+        fn position(&self) -> Vec3 {
+            *self
+        }
+    }
+
+    fn points() -> Vec<Vec3> {
+        let mut rng = thread_rng();
+        (0..500)
+            .map(|_| Vec3::new(rng.gen(), rng.gen(), rng.gen()))
+            .collect()
+    }
+
+    #[test]
+    fn within_matches_brute_force() {
+        let points = points();
+        let tree = KdTree::new(points.clone());
+        let p = Vec3::new(0.5, 0.5, 0.5);
+        let r2 = 0.04;
+        let mut found = 0;
+        tree.within(&p, r2, |_, d2| {
+            assert!(d2 <= r2);
+            found += 1;
+        });
+        let expected = points.iter().filter(|q| (**q - p).squared_length() <= r2).count();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let points = points();
+        let tree = KdTree::new(points.clone());
+        let p = Vec3::new(0.3, 0.6, 0.2);
+        let mut distances: Vec<f32> = points.iter().map(|q| (*q - p).squared_length()).collect();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let (found, r2) = tree.nearest(&p, 10, f32::MAX);
+        assert_eq!(found.len(), 10);
+        assert_eq!(r2, distances[9]);
+    }
+}
@@ -1,18 +1,41 @@
 use rand::prelude::*;
 
+mod aov;
+mod bdpt;
 mod camera;
+mod debug;
+mod denoise;
+mod exr;
+mod film;
+mod filter;
+mod hdr;
 mod hitable;
+mod integrator;
+mod kdtree;
 mod material;
+mod mlt;
+mod normalmap;
+mod onb;
+mod photon;
+mod png;
+mod ppm;
 mod ray;
+mod sampler;
 mod sphere;
 mod vec3;
 mod utils;
-mod scene;
+pub mod scene;
+pub mod settings;
+mod spectrum;
+mod texture;
+mod tonemap;
 
-use crate::hitable::{HitList, Hitable};
-use crate::material::{Dielectric, Lambertian, Material, Metal};
-use crate::ray::Ray;
+use crate::hitable::HitList;
+use crate::material::{
+    Coated, Dielectric, Dispersion, Lambertian, Material, Metal, Mix, OrenNayar, Retroreflective,
+};
 use crate::sphere::Sphere;
+use crate::texture::Texture;
 use crate::vec3::Vec3;
 
 use wasm_bindgen::prelude::*;
@@ -33,7 +56,9 @@ pub fn greet() {
     alert("Hello, wasm-placeholder!");
 }
 
-fn random_scene() -> HitList<Sphere> {
+/// The cover scene of Ray Tracing in One Weekend: a few large spheres
+/// among a field of small random ones.
+pub fn random_scene() -> HitList<Sphere> {
     let mut hitlist = HitList { list: Vec::new() };
     hitlist.list.push(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
@@ -119,30 +144,105 @@ fn random_scene() -> HitList<Sphere> {
     hitlist
 }
 
-fn color<T: Hitable>(r: Ray, world: &T, depth: u32) -> Vec3 {
-    match world.hit(&r, 0.001, std::f32::MAX) {
-        Some(x) => {
-            if depth < 50 {
-                match Material::scatter(x.material, &r, &x) {
-                    Some((attenuation, scattered)) => {
-                        attenuation * color(scattered, world, depth + 1)
-                    }
-                    None => Vec3::new(0.0, 0.0, 0.0),
-                }
-            } else {
-                return Vec3::new(0.0, 0.0, 0.0);
-            }
-        }
-        None => {
-            let unit_direction = r.direction().unit();
-            let t: f32 = 0.5 * (unit_direction.y() + 1.0);
-            return (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0);
-        }
-    }
+/// The cover scene's three large spheres dressed in the other material
+/// models, for trying them out: a checker of two diffuse materials for the
+/// ground, dispersive glass in the middle that splits light into colours
+/// when rendered spectrally, a varnished ball in place of the plain diffuse
+/// one, and a rough and a dusty, retro-reflective ball in front.
+pub fn demo_scene() -> HitList<Sphere> {
+    let mut hitlist = HitList { list: Vec::new() };
+    hitlist.list.push(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Material::Mix {
+            mat: Mix::new(
+                Material::Lambertian {
+                    mat: Lambertian::new(0.5, 0.5, 0.5),
+                },
+                Material::Lambertian {
+                    mat: Lambertian::new(0.2, 0.3, 0.1),
+                },
+                Texture::Checker {
+                    odd: Vec3::new(0.0, 0.0, 0.0),
+                    even: Vec3::new(1.0, 1.0, 1.0),
+                    scale: 10.0,
+                },
+            ),
+        },
+    ));
+    hitlist.list.push(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Material::Dielectric {
+            mat: Dielectric::dispersive(Dispersion::bk7()),
+        },
+    ));
+
+    hitlist.list.push(Sphere::new(
+        Vec3::new(-4.0, 1.0, 0.0),
+        1.0,
+        Material::Coated {
+            mat: Coated::new(
+                Material::Lambertian {
+                    mat: Lambertian::new(0.4, 0.2, 0.1),
+                },
+                1.5,
+            ),
+        },
+    ));
+
+    hitlist.list.push(Sphere::new(
+        Vec3::new(4.0, 1.0, 0.0),
+        1.0,
+        Material::Metal {
+            mat: Metal::new(0.7, 0.6, 0.5, 0.0),
+        },
+    ));
+
+    hitlist.list.push(Sphere::new(
+        Vec3::new(7.0, 0.5, 2.6),
+        0.5,
+        Material::OrenNayar {
+            mat: OrenNayar::new(0.6, 0.4, 0.3, 40.0),
+        },
+    ));
+
+    hitlist.list.push(Sphere::new(
+        Vec3::new(7.4, 0.5, 1.0),
+        0.5,
+        Material::Retroreflective {
+            mat: Retroreflective::new(0.3, 0.4, 0.6, 0.8),
+        },
+    ));
+
+    hitlist
 }
 
 #[wasm_bindgen]
+#[allow(deprecated)]
 pub fn scene_gen_json() -> JsValue {
     JsValue::from_serde(&random_scene()).unwrap()
 }
 
+/// `demo_scene`, for the renderer to load in place of the random scene.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn demo_scene_json() -> JsValue {
+    JsValue::from_serde(&demo_scene()).unwrap()
+}
+
+/// Encodes 8-bit RGBA pixels, rows from the top, as a PNG. `text` is an
+/// array of `[keyword, value]` pairs stored as text chunks. Throws if the
+/// pixels don't match the size or the text isn't valid for a PNG.
+#[wasm_bindgen]
+#[allow(deprecated)]
+pub fn encode_png(width: u32, height: u32, rgba: &[u8], text: JsValue) -> Result<Vec<u8>, JsValue> {
+    let text: Vec<(String, String)> = if text.is_undefined() || text.is_null() {
+        Vec::new()
+    } else {
+        text.into_serde()
+            .map_err(|e| JsValue::from_str(&format!("PNG text must be [keyword, value] pairs: {}", e)))?
+    };
+    let text: Vec<(&str, &str)> = text.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    png::encode_rgba8(width, height, rgba, &text).map_err(|e| JsValue::from_str(&e))
+}
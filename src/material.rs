@@ -1,30 +1,231 @@
 use crate::hitable::HitRecord;
-use crate::ray::Ray;
-use crate::vec3::{random_in_unit_sphere, reflect, refract, Vec3};
-use rand::prelude::*;
+use crate::normalmap::NormalMap;
+use crate::sampler::Sampler;
+use crate::texture::Texture;
+use crate::vec3::{random_cosine_direction, random_in_unit_sphere, random_unit_vector, reflect, refract, Vec3};
 use serde::{Serialize, Deserialize};
+use std::f32::consts::PI;
 
-pub trait MaterialRay {
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)>;
+/// Scattering functions work in a local shading frame: `wo` points back along
+/// the incoming ray, `wi` is the scattered direction, and the surface normal
+/// is +z.
+pub trait Bsdf {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample>;
+
+    /// BSDF value for a pair of directions. Specular lobes can't be evaluated
+    /// and contribute zero.
+    fn eval(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3;
+
+    /// Density `sample` picks `wi` with, zero for specular lobes.
+    fn pdf(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32;
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct BsdfSample {
+    pub wi: Vec3,
+    pub f: Vec3,
+    pub pdf: f32,
+    /// Set for delta lobes, where `f` already holds the path weight and
+    /// `pdf` is only a placeholder.
+    pub specular: bool,
+    /// Set when `wi` depends on the wavelength, so a spectral path can only
+    /// keep its hero wavelength.
+    pub dispersive: bool,
+}
+
+impl BsdfSample {
+    pub fn specular(wi: Vec3, weight: Vec3) -> BsdfSample {
+        BsdfSample {
+            wi,
+            f: weight,
+            pdf: 1.0,
+            specular: true,
+            dispersive: false,
+        }
+    }
+
+    /// Throughput multiplier for this sample, `f * cos / pdf`.
+    pub fn weight(&self) -> Vec3 {
+        if self.specular {
+            self.f
+        } else {
+            self.f * (self.wi.z().abs() / self.pdf)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Material {
     Lambertian { mat: Lambertian },
     Metal { mat: Metal },
     Dielectric { mat: Dielectric },
+    Mix { mat: Mix },
+    Coated { mat: Coated },
+    OrenNayar { mat: OrenNayar },
+    Retroreflective { mat: Retroreflective },
+    Subsurface { mat: Subsurface },
+    NormalMapped { mat: NormalMapped },
+    Cutout { mat: Cutout },
+    DiffuseLight { mat: DiffuseLight },
 }
 
 impl Material {
-    pub fn scatter(m: Material, r: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-        match m {
-            Material::Lambertian { mat } => mat.scatter(r, rec),
-            Material::Metal { mat } => mat.scatter(r, rec),
-            Material::Dielectric { mat } => mat.scatter(r, rec),
+    /// Radiance emitted from the surface, the same in every direction.
+    pub fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::DiffuseLight { mat } => mat.emit.value(rec.u, rec.v, &rec.p),
+            Material::NormalMapped { mat } => mat.base.emitted(rec),
+            Material::Cutout { mat } => mat.base.emitted(rec),
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        match self {
+            Material::DiffuseLight { .. } => true,
+            Material::NormalMapped { mat } => mat.base.is_emissive(),
+            Material::Cutout { mat } => mat.base.is_emissive(),
+            _ => false,
+        }
+    }
+
+    /// Whether the material has a non-specular lobe that photons can be
+    /// stored on and looked up from.
+    pub fn is_diffuse(&self) -> bool {
+        match self {
+            Material::Lambertian { .. }
+            | Material::OrenNayar { .. }
+            | Material::Retroreflective { .. }
+            | Material::Mix { .. }
+            | Material::Coated { .. } => true,
+            Material::NormalMapped { mat } => mat.base.is_diffuse(),
+            Material::Cutout { mat } => mat.base.is_diffuse(),
+            _ => false,
+        }
+    }
+
+    /// Base colour of the surface with lighting taken out, for the albedo
+    /// AOV. Clear glass is white and emitters are black.
+    pub fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::Lambertian { mat } => mat.albedo,
+            Material::Metal { mat } => mat.albedo,
+            Material::Dielectric { .. } => Vec3::new(1.0, 1.0, 1.0),
+            Material::Mix { mat } => {
+                let m = mat.weight(rec);
+                (1.0 - m) * mat.a.albedo(rec) + m * mat.b.albedo(rec)
+            }
+            Material::Coated { mat } => mat.base.albedo(rec),
+            Material::OrenNayar { mat } => mat.albedo,
+            Material::Retroreflective { mat } => mat.albedo,
+            Material::Subsurface { mat } => mat.albedo,
+            Material::NormalMapped { mat } => mat.base.albedo(rec),
+            Material::Cutout { mat } => mat.base.albedo(rec),
+            Material::DiffuseLight { .. } => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Whether a hit on this material counts, or passes straight through a
+    /// cut-out part of the surface.
+    pub fn opaque(&self, rec: &HitRecord) -> bool {
+        match self {
+            Material::Cutout { mat } => mat.opaque(rec) && mat.base.opaque(rec),
+            Material::NormalMapped { mat } => mat.base.opaque(rec),
+            Material::Mix { mat } => mat.opaque(rec),
+            Material::Coated { mat } => mat.base.opaque(rec),
+            _ => true,
+        }
+    }
+
+    /// Shading normal after any normal or bump map, for a hit on this material.
+    pub fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::NormalMapped { mat } => mat.map.shading_normal(rec),
+            Material::Cutout { mat } => mat.base.shading_normal(rec),
+            _ => rec.normal,
+        }
+    }
+
+    /// Participating medium filling the inside of the surface, if any.
+    pub fn medium(&self) -> Option<&Subsurface> {
+        match self {
+            Material::Subsurface { mat } => Some(mat),
+            Material::NormalMapped { mat } => mat.base.medium(),
+            Material::Cutout { mat } => mat.base.medium(),
+            _ => None,
+        }
+    }
+
+    fn bsdf(&self) -> &dyn Bsdf {
+        match self {
+            Material::Lambertian { mat } => mat,
+            Material::Metal { mat } => mat,
+            Material::Dielectric { mat } => mat,
+            Material::Mix { mat } => mat,
+            Material::Coated { mat } => mat,
+            Material::OrenNayar { mat } => mat,
+            Material::Retroreflective { mat } => mat,
+            Material::Subsurface { mat } => mat,
+            Material::NormalMapped { mat } => &*mat.base,
+            Material::Cutout { mat } => &*mat.base,
+            Material::DiffuseLight { mat } => mat,
         }
     }
 }
 
+impl Bsdf for Material {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        self.bsdf().sample(rec, wo, sampler)
+    }
+
+    fn eval(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        self.bsdf().eval(rec, wo, wi)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        self.bsdf().pdf(rec, wo, wi)
+    }
+}
+
+fn same_hemisphere(a: &Vec3, b: &Vec3) -> bool {
+    a.z() * b.z() > 0.0
+}
+
+/// Cosine-weighted sample on the same side of the surface as `wo`, so the
+/// diffuse models below are two-sided.
+fn cosine_sample(wo: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+    let wi = random_cosine_direction(sampler);
+    if wo.z() < 0.0 {
+        Vec3::new(wi.x(), wi.y(), -wi.z())
+    } else {
+        wi
+    }
+}
+
+fn cosine_pdf(wo: &Vec3, wi: &Vec3) -> f32 {
+    if same_hemisphere(wo, wi) {
+        wi.z().abs() / PI
+    } else {
+        0.0
+    }
+}
+
+/// Samples a diffuse lobe by cosine weighting, filling in `f` and `pdf` from
+/// the material's own `eval`.
+fn diffuse_sample(m: &dyn Bsdf, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+    let wi = cosine_sample(wo, sampler);
+    let pdf = cosine_pdf(wo, &wi);
+    if pdf == 0.0 {
+        return None;
+    }
+    Some(BsdfSample {
+        wi,
+        f: m.eval(rec, wo, &wi),
+        pdf,
+        specular: false,
+        dispersive: false,
+    })
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Lambertian {
     albedo: Vec3,
@@ -38,11 +239,125 @@ impl Lambertian {
     }
 }
 
-impl MaterialRay for Lambertian {
-    fn scatter(&self, _r: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-        let target = rec.p + rec.normal + random_in_unit_sphere();
-        let scattered = Ray::new(rec.p, target - rec.p);
-        Some((self.albedo, scattered))
+impl Bsdf for Lambertian {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        diffuse_sample(self, rec, wo, sampler)
+    }
+
+    fn eval(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        if !same_hemisphere(wo, wi) {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.albedo / PI
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        cosine_pdf(wo, wi)
+    }
+}
+
+/// Oren-Nayar rough diffuse. `sigma` is the standard deviation of the
+/// microfacet slope angle in degrees; zero reduces to Lambertian.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct OrenNayar {
+    albedo: Vec3,
+    sigma: f32,
+}
+
+impl OrenNayar {
+    pub fn new(x: f32, y: f32, z: f32, sigma: f32) -> OrenNayar {
+        OrenNayar {
+            albedo: Vec3::new(x, y, z),
+            sigma,
+        }
+    }
+
+    /// Angular factor multiplying `albedo / PI`, for local-frame directions.
+    fn factor(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let sigma = self.sigma.to_radians();
+        let sigma2 = sigma * sigma;
+        let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        let sin_theta_i = (1.0 - wi.z() * wi.z()).max(0.0).sqrt();
+        let sin_theta_o = (1.0 - wo.z() * wo.z()).max(0.0).sqrt();
+        let mut max_cos = 0.0;
+        if sin_theta_i > 1e-4 && sin_theta_o > 1e-4 {
+            let d_cos = (wi.x() * wo.x() + wi.y() * wo.y()) / (sin_theta_i * sin_theta_o);
+            max_cos = d_cos.max(0.0);
+        }
+        let (sin_alpha, sin_beta, cos_beta) = if wi.z().abs() > wo.z().abs() {
+            (sin_theta_o, sin_theta_i, wi.z().abs())
+        } else {
+            (sin_theta_i, sin_theta_o, wo.z().abs())
+        };
+        // Both directions grazing: the term vanishes with `cos` in the
+        // rendering equation, and dividing by it would not be finite
+        let tan_beta = if cos_beta > 1e-4 { sin_beta / cos_beta } else { 0.0 };
+        a + b * max_cos * sin_alpha * tan_beta
+    }
+}
+
+impl Bsdf for OrenNayar {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        diffuse_sample(self, rec, wo, sampler)
+    }
+
+    fn eval(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        if !same_hemisphere(wo, wi) {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.albedo * (self.factor(wo, wi) / PI)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        cosine_pdf(wo, wi)
+    }
+}
+
+/// Burley's diffuse term from the Disney BRDF. Rough surfaces get brighter
+/// towards grazing and retro-reflective angles, which suits dusty surfaces
+/// like the moon.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Retroreflective {
+    albedo: Vec3,
+    roughness: f32,
+}
+
+impl Retroreflective {
+    pub fn new(x: f32, y: f32, z: f32, roughness: f32) -> Retroreflective {
+        Retroreflective {
+            albedo: Vec3::new(x, y, z),
+            roughness: roughness.clamp(0.0, 1.0),
+        }
+    }
+
+    fn factor(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        let h = *wo + *wi;
+        if h.squared_length() == 0.0 {
+            return 1.0;
+        }
+        let cos_d = wi.dot(&h.unit());
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fl = 1.0 + (fd90 - 1.0) * (1.0 - wi.z().abs()).powi(5);
+        let fv = 1.0 + (fd90 - 1.0) * (1.0 - wo.z().abs()).powi(5);
+        fl * fv
+    }
+}
+
+impl Bsdf for Retroreflective {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        diffuse_sample(self, rec, wo, sampler)
+    }
+
+    fn eval(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        if !same_hemisphere(wo, wi) {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.albedo * (self.factor(wo, wi) / PI)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        cosine_pdf(wo, wi)
     }
 }
 
@@ -66,67 +381,747 @@ impl Metal {
     }
 }
 
-impl MaterialRay for Metal {
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-        let reflected = reflect(r.direction().unit(), rec.normal);
-        let scattered = Ray::new(rec.p, reflected + self.fuzz * random_in_unit_sphere());
-        Some((self.albedo, scattered))
+/// The fuzzed reflection has no closed-form density, so metal is treated as a
+/// specular lobe.
+impl Bsdf for Metal {
+    fn sample(&self, _rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let reflected = reflect(-*wo, Vec3::new(0.0, 0.0, 1.0));
+        let wi = reflected + self.fuzz * random_in_unit_sphere(sampler);
+        Some(BsdfSample::specular(wi.unit(), self.albedo))
+    }
+
+    fn eval(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> f32 {
+        0.0
     }
 }
 
+/// Wavelength-dependent index of refraction, with wavelengths in micrometres.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum Dispersion {
+    Cauchy { a: f32, b: f32 },
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.039_612, 0.231_792_3, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    pub fn diamond() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030_625, 0.011_236, 0.0],
+        }
+    }
+
+    /// Index of refraction at a wavelength in nanometres.
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let l = wavelength / 1000.0;
+        let l2 = l * l;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>();
+                n2.sqrt()
+            }
+        }
+    }
+}
+
+/// Wavelengths in nanometres standing in for the RGB channels when a
+/// wavelength-dependent effect is rendered without spectral sampling.
+const RGB_WAVELENGTHS: [f32; 3] = [630.0, 532.0, 465.0];
+
+/// A thin transparent film on a surface, such as an anti-reflection coating or
+/// a soap bubble wall. Interference between the film's two interfaces tints
+/// the reflection depending on thickness, angle and wavelength.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ThinFilm {
+    /// Film thickness in nanometres.
+    thickness: f32,
+    ior: f32,
+}
+
+impl ThinFilm {
+    pub fn new(thickness: f32, ior: f32) -> ThinFilm {
+        ThinFilm { thickness, ior }
+    }
+
+    /// Reflectance for light arriving through a medium of index `n_incident`
+    /// onto the film over a substrate of index `n_substrate`. Without a
+    /// wavelength each RGB channel uses its own representative wavelength.
+    pub fn reflectance(
+        &self,
+        cos_i: f32,
+        n_incident: f32,
+        n_substrate: f32,
+        wavelength: Option<f32>,
+    ) -> Vec3 {
+        let r = |l: f32| self.reflectance_at(cos_i, n_incident, n_substrate, l);
+        match wavelength {
+            Some(l) => {
+                let r = r(l);
+                Vec3::new(r, r, r)
+            }
+            None => Vec3::new(
+                r(RGB_WAVELENGTHS[0]),
+                r(RGB_WAVELENGTHS[1]),
+                r(RGB_WAVELENGTHS[2]),
+            ),
+        }
+    }
+
+    /// Airy summation of the multiple reflections inside the film, averaged
+    /// over s and p polarisation.
+    fn reflectance_at(&self, cos_i: f32, n1: f32, n3: f32, wavelength: f32) -> f32 {
+        let n2 = self.ior;
+        let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+        let sin2_film = (n1 / n2) * (n1 / n2) * sin2_i;
+        let sin2_t = (n1 / n3) * (n1 / n3) * sin2_i;
+        if sin2_film >= 1.0 || sin2_t >= 1.0 {
+            return 1.0;
+        }
+        let cos_film = (1.0 - sin2_film).sqrt();
+        let cos_t = (1.0 - sin2_t).sqrt();
+        let phase = 4.0 * PI * n2 * self.thickness * cos_film / wavelength;
+        let airy = |r12: f32, r23: f32| {
+            let cross = 2.0 * r12 * r23 * phase.cos();
+            (r12 * r12 + r23 * r23 + cross) / (1.0 + r12 * r12 * r23 * r23 + cross)
+        };
+        let rs = airy(
+            fresnel_s(n1, cos_i, n2, cos_film),
+            fresnel_s(n2, cos_film, n3, cos_t),
+        );
+        let rp = airy(
+            fresnel_p(n1, cos_i, n2, cos_film),
+            fresnel_p(n2, cos_film, n3, cos_t),
+        );
+        0.5 * (rs + rp)
+    }
+}
+
+fn fresnel_s(n1: f32, cos1: f32, n2: f32, cos2: f32) -> f32 {
+    (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2)
+}
+
+fn fresnel_p(n1: f32, cos1: f32, n2: f32, cos2: f32) -> f32 {
+    (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2)
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct Dielectric {
     ref_idx: f32,
+    #[serde(default)]
+    dispersion: Option<Dispersion>,
+    #[serde(default)]
+    film: Option<ThinFilm>,
 }
 
 impl Dielectric {
     pub fn new(ref_idx: f32) -> Dielectric {
-        Dielectric { ref_idx }
+        Dielectric {
+            ref_idx,
+            dispersion: None,
+            film: None,
+        }
+    }
+
+    /// Dispersive glass. RGB renders use the index at the sodium d-line.
+    pub fn dispersive(dispersion: Dispersion) -> Dielectric {
+        Dielectric {
+            ref_idx: dispersion.ior(587.6),
+            dispersion: Some(dispersion),
+            film: None,
+        }
+    }
+
+    pub fn with_film(mut self, film: ThinFilm) -> Dielectric {
+        self.film = Some(film);
+        self
     }
 
     pub fn schlick(&self, cosine: f32) -> f32 {
-        let mut r0: f32 = (1.0 - self.ref_idx) / (1.0 + self.ref_idx);
-        r0 *= r0;
-        r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+        schlick(self.ref_idx, cosine)
     }
+
+    /// Fresnel reflectance for light arriving from outside at `cosine`,
+    /// including any thin-film coating.
+    pub fn reflectance(&self, cosine: f32, wavelength: Option<f32>) -> Vec3 {
+        let ref_idx = self.ior(wavelength);
+        match self.film {
+            Some(film) => film.reflectance(cosine, 1.0, ref_idx, wavelength),
+            None => {
+                let r = schlick(ref_idx, cosine);
+                Vec3::new(r, r, r)
+            }
+        }
+    }
+
+    fn ior(&self, wavelength: Option<f32>) -> f32 {
+        match (self.dispersion, wavelength) {
+            (Some(d), Some(l)) => d.ior(l),
+            _ => self.ref_idx,
+        }
+    }
+}
+
+fn average(v: &Vec3) -> f32 {
+    (v.x() + v.y() + v.z()) / 3.0
+}
+
+/// Value in [0, 1) that looks random but depends only on which cell of a fine
+/// grid `p` falls in.
+fn hash(p: &Vec3, salt: u32) -> f32 {
+    let mut h: u32 = 0x811c_9dc5 ^ salt;
+    for c in [p.x(), p.y(), p.z()].iter() {
+        h ^= (c * 1.0e4).floor() as i32 as u32;
+        h = h.wrapping_mul(0x0100_0193);
+    }
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+fn schlick(ref_idx: f32, cosine: f32) -> f32 {
+    let mut r0: f32 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    r0 *= r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
-impl MaterialRay for Dielectric {
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
+impl Bsdf for Dielectric {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let ref_idx = self.ior(rec.wavelength);
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let direction = -*wo;
         let outward_normal: Vec3;
         let ni_over_nt: f32;
         let attenuation = Vec3::new(1.0, 1.0, 1.0);
         let cosine: f32;
-        let reflected: Vec3 = reflect(r.direction(), rec.normal);
-        if r.direction().dot(&rec.normal) > 0.0 {
-            outward_normal = -rec.normal;
-            ni_over_nt = self.ref_idx;
-            cosine = self.ref_idx * r.direction().dot(&rec.normal) / r.direction().length();
+        let reflected: Vec3 = reflect(direction, normal);
+        if direction.z() > 0.0 {
+            outward_normal = -normal;
+            ni_over_nt = ref_idx;
+            cosine = ref_idx * direction.z();
         } else {
-            outward_normal = rec.normal;
-            ni_over_nt = 1.0 / self.ref_idx;
-            cosine = -r.direction().dot(&rec.normal) / r.direction().length();
-        }
-        let reflect_prob: f32;
-        let scattered: Ray;
-        let refracted: Vec3;
-        match refract(&r.direction(), &outward_normal, ni_over_nt) {
-            Some(x) => {
-                reflect_prob = self.schlick(cosine);
-                refracted = x;
-                let mut rng = thread_rng();
-                if rng.gen::<f32>() < reflect_prob {
-                    scattered = Ray::new(rec.p, reflected);
-                    return Some((attenuation, scattered));
+            outward_normal = normal;
+            ni_over_nt = 1.0 / ref_idx;
+            cosine = -direction.z();
+        }
+        let fresnel = match self.film {
+            Some(film) => {
+                let (n_incident, n_substrate) = if direction.z() > 0.0 {
+                    (ref_idx, 1.0)
                 } else {
-                    scattered = Ray::new(rec.p, refracted);
-                    return Some((attenuation, scattered));
-                }
+                    (1.0, ref_idx)
+                };
+                film.reflectance(direction.z().abs(), n_incident, n_substrate, rec.wavelength)
             }
             None => {
-                scattered = Ray::new(rec.p, reflected);
-                return Some((attenuation, scattered));
+                let r = schlick(ref_idx, cosine);
+                Vec3::new(r, r, r)
             }
+        };
+        let (wi, weight) = match refract(&direction, &outward_normal, ni_over_nt) {
+            Some(refracted) => {
+                // A tinted film reflects each channel differently, so pick by
+                // the average and reweight per channel
+                let reflect_prob = average(&fresnel);
+                if sampler.next_1d() < reflect_prob {
+                    (reflected, fresnel / reflect_prob)
+                } else {
+                    let transmitted = attenuation - fresnel;
+                    (refracted.unit(), transmitted / (1.0 - reflect_prob))
+                }
+            }
+            None => (reflected, attenuation),
+        };
+        let mut s = BsdfSample::specular(wi, weight);
+        s.dispersive =
+            (self.dispersion.is_some() || self.film.is_some()) && rec.wavelength.is_some();
+        Some(s)
+    }
+
+    fn eval(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> f32 {
+        0.0
+    }
+}
+
+/// Area light. It emits from both sides and doesn't reflect anything.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DiffuseLight {
+    emit: Texture,
+}
+
+impl DiffuseLight {
+    pub fn new(x: f32, y: f32, z: f32) -> DiffuseLight {
+        DiffuseLight {
+            emit: Texture::Constant {
+                color: Vec3::new(x, y, z),
+            },
         }
     }
 }
+
+impl Bsdf for DiffuseLight {
+    fn sample(&self, _rec: &HitRecord, _wo: &Vec3, _sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        None
+    }
+
+    fn eval(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> f32 {
+        0.0
+    }
+}
+
+/// Translucent material such as skin, wax, marble or milk. The surface is a
+/// smooth dielectric boundary and the inside is a scattering medium that light
+/// random-walks through until it finds its way out again.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Subsurface {
+    boundary: Dielectric,
+    /// Single-scattering albedo per channel.
+    albedo: Vec3,
+    /// Mean free path per channel, in scene units.
+    mfp: Vec3,
+}
+
+/// Outcome of one free-flight step through a medium.
+pub struct FreeFlight {
+    pub distance: f32,
+    pub weight: Vec3,
+    /// True when the walk scatters inside the medium before `distance`
+    /// reaches the surface.
+    pub scattered: bool,
+}
+
+impl Subsurface {
+    pub fn new(ref_idx: f32, albedo: Vec3, mfp: Vec3) -> Subsurface {
+        Subsurface {
+            boundary: Dielectric::new(ref_idx),
+            albedo,
+            mfp,
+        }
+    }
+
+    /// Samples how far light travels before its next scattering event, with
+    /// the surface `t_max` away. A channel is picked at random for the
+    /// distance and the weight uses the average density over all three, so
+    /// strongly chromatic paths don't produce fireflies.
+    pub fn sample_free_flight(&self, t_max: f32, sampler: &mut dyn Sampler) -> FreeFlight {
+        let sigma_t = Vec3::new(1.0 / self.mfp.x(), 1.0 / self.mfp.y(), 1.0 / self.mfp.z());
+        let (u1, u2) = sampler.next_2d();
+        let channel = match (u1 * 3.0) as u32 {
+            0 => sigma_t.x(),
+            1 => sigma_t.y(),
+            _ => sigma_t.z(),
+        };
+        let t = -(1.0 - u2).ln() / channel;
+        let transmittance = |d: f32| {
+            Vec3::new(
+                (-sigma_t.x() * d).exp(),
+                (-sigma_t.y() * d).exp(),
+                (-sigma_t.z() * d).exp(),
+            )
+        };
+        if t < t_max {
+            let tr = transmittance(t);
+            let pdf = average(&(sigma_t * tr));
+            FreeFlight {
+                distance: t,
+                weight: self.albedo * sigma_t * tr / pdf,
+                scattered: true,
+            }
+        } else {
+            let tr = transmittance(t_max);
+            FreeFlight {
+                distance: t_max,
+                weight: tr / average(&tr),
+                scattered: false,
+            }
+        }
+    }
+
+    /// Isotropic phase function sample, in world space.
+    pub fn sample_phase(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        random_unit_vector(sampler)
+    }
+}
+
+impl Bsdf for Subsurface {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        self.boundary.sample(rec, wo, sampler)
+    }
+
+    fn eval(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> Vec3 {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _rec: &HitRecord, _wo: &Vec3, _wi: &Vec3) -> f32 {
+        0.0
+    }
+}
+
+/// Any material with a normal or bump map applied. Scattering is left to the
+/// base material; the integrator asks for the shading normal before building
+/// the local frame.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NormalMapped {
+    base: Box<Material>,
+    map: NormalMap,
+}
+
+impl NormalMapped {
+    pub fn new(base: Material, map: NormalMap) -> NormalMapped {
+        NormalMapped {
+            base: Box::new(base),
+            map,
+        }
+    }
+}
+
+/// Any material with an opacity mask, for leaves, fences and decals. Hits
+/// where the mask is below `threshold` are skipped; without a threshold the
+/// mask is the probability of a hit, which keeps soft edges unbiased. The
+/// stochastic test hashes the hit position, so every ray through one point of
+/// the surface agrees on whether it is there.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Cutout {
+    base: Box<Material>,
+    opacity: Texture,
+    #[serde(default)]
+    threshold: Option<f32>,
+}
+
+impl Cutout {
+    pub fn new(base: Material, opacity: Texture, threshold: Option<f32>) -> Cutout {
+        Cutout {
+            base: Box::new(base),
+            opacity,
+            threshold,
+        }
+    }
+
+    fn opaque(&self, rec: &HitRecord) -> bool {
+        let alpha = self.opacity.mask(rec.u, rec.v, &rec.p);
+        match self.threshold {
+            Some(threshold) => alpha >= threshold,
+            None => alpha >= 1.0 || hash(&rec.p, 0) < alpha,
+        }
+    }
+}
+
+/// Blends two materials, with the mask giving the weight of `b`. Sampling
+/// picks one of the two stochastically.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Mix {
+    a: Box<Material>,
+    b: Box<Material>,
+    mask: Texture,
+}
+
+impl Mix {
+    pub fn new(a: Material, b: Material, mask: Texture) -> Mix {
+        Mix {
+            a: Box::new(a),
+            b: Box::new(b),
+            mask,
+        }
+    }
+
+    fn weight(&self, rec: &HitRecord) -> f32 {
+        self.mask.mask(rec.u, rec.v, &rec.p).clamp(0.0, 1.0)
+    }
+
+    /// Where only one of the materials is there, the hit counts with that
+    /// material's weight, decided by hashing the position like `Cutout`
+    /// but independently of it.
+    fn opaque(&self, rec: &HitRecord) -> bool {
+        let (a, b) = (self.a.opaque(rec), self.b.opaque(rec));
+        if a == b {
+            return a;
+        }
+        let m = self.weight(rec);
+        let coverage = if b { m } else { 1.0 - m };
+        coverage >= 1.0 || hash(&rec.p, 1) < coverage
+    }
+}
+
+impl Bsdf for Mix {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let chosen = if sampler.next_1d() < self.weight(rec) {
+            &self.b
+        } else {
+            &self.a
+        };
+        let mut s = chosen.sample(rec, wo, sampler)?;
+        if !s.specular {
+            s.f = self.eval(rec, wo, &s.wi);
+            s.pdf = self.pdf(rec, wo, &s.wi);
+        }
+        Some(s)
+    }
+
+    fn eval(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let m = self.weight(rec);
+        (1.0 - m) * self.a.eval(rec, wo, wi) + m * self.b.eval(rec, wo, wi)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        let m = self.weight(rec);
+        (1.0 - m) * self.a.pdf(rec, wo, wi) + m * self.b.pdf(rec, wo, wi)
+    }
+}
+
+/// A clear dielectric coat over any base material. Light either reflects off
+/// the coat with the Fresnel probability or passes through to the base, and
+/// whatever the base scatters loses the coat's Fresnel reflectance again on the
+/// way out, so the layer never adds energy.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Coated {
+    base: Box<Material>,
+    coat: Dielectric,
+}
+
+impl Coated {
+    pub fn new(base: Material, ref_idx: f32) -> Coated {
+        Coated {
+            base: Box::new(base),
+            coat: Dielectric::new(ref_idx),
+        }
+    }
+
+    pub fn with_film(mut self, film: ThinFilm) -> Coated {
+        self.coat = self.coat.with_film(film);
+        self
+    }
+
+    fn transmittance(&self, rec: &HitRecord, w: &Vec3) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0) - self.coat.reflectance(w.z().abs(), rec.wavelength)
+    }
+}
+
+impl Bsdf for Coated {
+    fn sample(&self, rec: &HitRecord, wo: &Vec3, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        if wo.z() <= 0.0 {
+            return self.base.sample(rec, wo, sampler);
+        }
+        let fresnel = self.coat.reflectance(wo.z(), rec.wavelength);
+        let reflect_prob = average(&fresnel);
+        if sampler.next_1d() < reflect_prob {
+            let reflected = Vec3::new(-wo.x(), -wo.y(), wo.z());
+            let mut s = BsdfSample::specular(reflected, fresnel / reflect_prob);
+            s.dispersive = self.coat.film.is_some() && rec.wavelength.is_some();
+            return Some(s);
+        }
+        let mut s = self.base.sample(rec, wo, sampler)?;
+        if s.specular {
+            s.f *= self.transmittance(rec, wo) / (1.0 - reflect_prob);
+            if s.wi.z() > 0.0 {
+                s.f *= self.transmittance(rec, &s.wi);
+            }
+        } else {
+            s.f = self.eval(rec, wo, &s.wi);
+            s.pdf = self.pdf(rec, wo, &s.wi);
+        }
+        s.dispersive |= self.coat.film.is_some() && rec.wavelength.is_some();
+        Some(s)
+    }
+
+    fn eval(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let f = self.base.eval(rec, wo, wi);
+        if wo.z() <= 0.0 {
+            return f;
+        }
+        f * self.transmittance(rec, wo) * self.transmittance(rec, wi)
+    }
+
+    fn pdf(&self, rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f32 {
+        let pdf = self.base.pdf(rec, wo, wi);
+        if wo.z() <= 0.0 {
+            return pdf;
+        }
+        pdf * (1.0 - average(&self.coat.reflectance(wo.z(), rec.wavelength)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        schlick, Bsdf, Cutout, Lambertian, Material, Mix, OrenNayar, Retroreflective, Subsurface, ThinFilm,
+    };
+    use crate::texture::Texture;
+    use super::Vec3;
+    use crate::hitable::HitRecord;
+    use crate::sampler::Independent;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn oren_nayar_smooth_is_lambertian() {
+        let m = Material::OrenNayar {
+            mat: OrenNayar::new(0.5, 0.5, 0.5, 0.0),
+        };
+        let rec = HitRecord::facing_z(&m);
+        let wo = Vec3::new(0.6, 0.0, 0.8);
+        let wi = Vec3::new(-0.6, 0.0, 0.8);
+        assert!((m.eval(&rec, &wo, &wi) - Vec3::new(0.5 / PI, 0.5 / PI, 0.5 / PI)).length() < 1e-6);
+        assert!((m.pdf(&rec, &wo, &wi) - 0.8 / PI).abs() < 1e-6);
+        assert_eq!(m.eval(&rec, &wo, &Vec3::new(0.0, 0.6, -0.8)), Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn oren_nayar_is_finite_at_grazing_angles() {
+        let m = Material::OrenNayar {
+            mat: OrenNayar::new(0.5, 0.5, 0.5, 30.0),
+        };
+        let rec = HitRecord::facing_z(&m);
+        let wo = Vec3::new(1.0, 0.0, 1e-7);
+        let f = m.eval(&rec, &wo, &wo);
+        assert!(f.x().is_finite() && f.x() >= 0.0);
+    }
+
+    #[test]
+    fn retroreflective_brightens_grazing_retro_reflection() {
+        let rough = Material::Retroreflective {
+            mat: Retroreflective::new(0.5, 0.5, 0.5, 1.0),
+        };
+        let smooth = Material::Retroreflective {
+            mat: Retroreflective::new(0.5, 0.5, 0.5, 0.0),
+        };
+        let rec = HitRecord::facing_z(&rough);
+        let lambert = 0.5 / PI;
+
+        // Straight down the normal every roughness is Lambertian
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        assert!((rough.eval(&rec, &n, &n).x() - lambert).abs() < 1e-6);
+        assert!((smooth.eval(&rec, &n, &n).x() - lambert).abs() < 1e-6);
+
+        // Towards grazing, rough surfaces reflect more back at the light and
+        // smooth ones less
+        let grazing = Vec3::new(0.8, 0.0, 0.6);
+        assert!(rough.eval(&rec, &grazing, &grazing).x() > lambert);
+        assert!(smooth.eval(&rec, &grazing, &grazing).x() < lambert);
+
+        let wi = Vec3::new(-0.36, 0.48, 0.8);
+        assert!((rough.eval(&rec, &grazing, &wi) - rough.eval(&rec, &wi, &grazing)).length() < 1e-6);
+        assert_eq!(rough.eval(&rec, &grazing, &Vec3::new(0.0, 0.6, -0.8)), Vec3::new(0.0, 0.0, 0.0));
+        assert!((rough.pdf(&rec, &grazing, &wi) - 0.8 / PI).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mix_sees_cutouts_inside_it() {
+        let mix = |weight: f32| Material::Mix {
+            mat: Mix::new(
+                Material::Cutout {
+                    mat: Cutout::new(
+                        Material::Lambertian {
+                            mat: Lambertian::new(0.5, 0.5, 0.5),
+                        },
+                        Texture::Constant {
+                            color: Vec3::new(0.0, 0.0, 0.0),
+                        },
+                        Some(0.5),
+                    ),
+                },
+                Material::Lambertian {
+                    mat: Lambertian::new(0.5, 0.5, 0.5),
+                },
+                Texture::Constant {
+                    color: Vec3::new(weight, weight, weight),
+                },
+            ),
+        };
+        let hole = mix(0.0);
+        assert!(!hole.opaque(&HitRecord::facing_z(&hole)));
+        let solid = mix(1.0);
+        assert!(solid.opaque(&HitRecord::facing_z(&solid)));
+
+        // Half of a half-covered surface is there
+        let half = mix(0.5);
+        let mut rec = HitRecord::facing_z(&half);
+        let n = 4000;
+        let hits = (0..n)
+            .filter(|i| {
+                rec.p = Vec3::new(*i as f32 * 0.013, (*i as f32 * 0.7).sin(), 0.0);
+                half.opaque(&rec)
+            })
+            .count();
+        assert!((hits as f32 / n as f32 - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn lambertian_sample_matches_eval_and_pdf() {
+        let m = Material::Lambertian {
+            mat: Lambertian::new(0.8, 0.4, 0.2),
+        };
+        let rec = HitRecord::facing_z(&m);
+        let wo = Vec3::new(0.0, 0.6, 0.8);
+        let mut sampler = Independent::new();
+        for _ in 0..100 {
+            let s = m.sample(&rec, &wo, &mut sampler).unwrap();
+            assert!(!s.specular);
+            assert!(s.wi.z() > 0.0);
+            assert!((s.pdf - m.pdf(&rec, &wo, &s.wi)).abs() < 1e-6);
+            assert!((s.weight() - Vec3::new(0.8, 0.4, 0.2)).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn free_flight_is_unbiased() {
+        // The expected weight of reaching the surface is the transmittance,
+        // and with a white albedo adding scattering makes it one
+        let m = Subsurface::new(1.3, Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 1.0, 2.0));
+        let n = 20000;
+        let mut escaped = Vec3::new(0.0, 0.0, 0.0);
+        let mut total = Vec3::new(0.0, 0.0, 0.0);
+        let mut sampler = Independent::new();
+        for _ in 0..n {
+            let f = m.sample_free_flight(1.0, &mut sampler);
+            if f.scattered {
+                assert!(f.distance < 1.0);
+            } else {
+                escaped += f.weight;
+            }
+            total += f.weight;
+        }
+        escaped /= n as f32;
+        total /= n as f32;
+        let expected = Vec3::new((-2.0f32).exp(), (-1.0f32).exp(), (-0.5f32).exp());
+        assert!((escaped - expected).length() < 0.02);
+        assert!((total - Vec3::new(1.0, 1.0, 1.0)).length() < 0.03);
+    }
+
+    #[test]
+    fn thin_film_vanishing_thickness() {
+        // With no thickness the film drops out and only the outer interfaces remain
+        let film = ThinFilm::new(0.0, 1.33);
+        let r = film.reflectance(1.0, 1.0, 1.5, Some(500.0));
+        assert!((r.x() - schlick(1.5, 1.0)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn thin_film_quarter_wave_cancels() {
+        // A quarter-wave film with index sqrt(n) is an ideal anti-reflection coating
+        let n = 1.5_f32;
+        let film_ior = n.sqrt();
+        let film = ThinFilm::new(550.0 / (4.0 * film_ior), film_ior);
+        assert!(film.reflectance(1.0, 1.0, n, Some(550.0)).x() < 1e-5);
+        assert!(film.reflectance(1.0, 1.0, n, None).z() > 1e-3);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::hitable::{HitList, Hitable};
+use crate::integrator::PathTracer;
+use crate::ray::Ray;
+use crate::sampler::{mix, Sampler};
+use crate::settings::RenderSettings;
+use crate::spectrum::{luminance, ColorMode};
+use crate::vec3::Vec3;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+
+/// One coordinate of the primary sample vector, with the state needed to
+/// undo a rejected mutation.
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    last_modified: u64,
+    value_backup: f32,
+    modified_backup: u64,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.value_backup = self.value;
+        self.modified_backup = self.last_modified;
+    }
+
+    fn restore(&mut self) {
+        self.value = self.value_backup;
+        self.last_modified = self.modified_backup;
+    }
+}
+
+/// Sampler handing out the coordinates of a point in primary sample space
+/// that Metropolis mutations move around. Coordinates are created and
+/// mutated lazily, the first time a path asks for them in an iteration, so
+/// paths of any length work.
+pub struct MltSampler {
+    rng: StdRng,
+    x: Vec<PrimarySample>,
+    sigma: f32,
+    large_step_probability: f32,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    pub fn new(seed: u64, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler {
+            rng: StdRng::seed_from_u64(seed),
+            x: Vec::new(),
+            sigma,
+            large_step_probability,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        }
+    }
+
+    /// Proposes the next point: either a fresh uniform one or a small
+    /// perturbation of the current one.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for x in self.x.iter_mut() {
+            if x.last_modified == self.iteration {
+                x.restore();
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// Brings coordinate `i` up to date with the current iteration.
+    fn ensure_ready(&mut self, i: usize) {
+        if i >= self.x.len() {
+            self.x.resize(i + 1, PrimarySample::default());
+        }
+        let mut x = self.x[i];
+        // A large step accepted since this coordinate was last used replaced
+        // the whole vector, including it
+        if x.last_modified < self.last_large_step {
+            x.value = self.rng.gen();
+            x.last_modified = self.last_large_step;
+        }
+
+        x.backup();
+        if self.large_step {
+            x.value = self.rng.gen();
+        } else {
+            // Small steps this coordinate missed compose into one Gaussian
+            // step with the variance of all of them
+            let missed = (self.iteration - x.last_modified) as f32;
+            let sigma = self.sigma * missed.sqrt();
+            x.value += self.normal() * sigma;
+            x.value -= x.value.floor();
+        }
+        x.last_modified = self.iteration;
+        self.x[i] = x;
+    }
+
+    /// Draws the sampler's random numbers from `seed` from now on, so
+    /// chains replayed from the same start go their own ways.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Standard normal deviate by the Box-Muller transform.
+    fn normal(&mut self) -> f32 {
+        let u1 = 1.0 - self.rng.gen::<f32>();
+        let u2: f32 = self.rng.gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_1d(&mut self) -> f32 {
+        let i = self.index;
+        self.index += 1;
+        self.ensure_ready(i);
+        // Wrapping can land exactly on 1.0 in f32
+        self.x[i].value.min(1.0 - f32::EPSILON)
+    }
+}
+
+/// A Markov chain's current path: where it landed, its color and the
+/// luminance the chain is distributed by.
+struct Chain {
+    sampler: MltSampler,
+    x: f32,
+    color: Vec3,
+    y: f32,
+}
+
+/// What a row keeps from one render to the next: its chains, which carry on
+/// where they stopped, and a running mean of the luminance of independent
+/// paths, which normalizes them.
+struct Row {
+    chains: Vec<Chain>,
+    rng: StdRng,
+    luminance: f64,
+    paths: u64,
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.) driving
+/// the path tracer. Each image row is its own sample space: the first time a
+/// row is rendered, a bootstrap pass estimates its mean brightness and
+/// starts Markov chains, which spend more samples on the paths that carry
+/// the most light. Rendering the row again runs the same chains further.
+/// Large steps are independent paths too, so they keep refining the mean.
+pub struct Mlt {
+    path: PathTracer,
+    bootstrap: u32,
+    chains: u32,
+    large_step: f32,
+    sigma: f32,
+    /// State of every row rendered so far, by row.
+    rows: RefCell<Vec<Option<Row>>>,
+}
+
+impl Mlt {
+    pub fn new(settings: &RenderSettings) -> Mlt {
+        Mlt {
+            path: PathTracer::new(settings),
+            bootstrap: settings.mlt_bootstrap.max(1),
+            chains: settings.mlt_chains.max(1),
+            large_step: settings.mlt_large_step,
+            sigma: settings.mlt_sigma,
+            rows: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Radiance along `r` from the underlying path tracer.
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> C::Value {
+        self.path.li(r, world, lights, mode, sampler)
+    }
+
+    /// Renders row `row`, `width` pixels wide, with `spp` mutations per
+    /// pixel. `contribution` draws a sample from the sampler and returns the
+    /// horizontal position it landed at, in pixels, and its color. It must
+    /// take every random number from the sampler so a seed replays a path.
+    /// `seed` picks the row's bootstrap paths the first time it is rendered.
+    pub fn render_row<F>(&self, row: u32, width: u32, seed: u64, spp: u32, mut contribution: F) -> Vec<Vec3>
+    where
+        F: FnMut(&mut dyn Sampler) -> (f32, Vec3),
+    {
+        let mut pixels = vec![Vec3::new(0.0, 0.0, 0.0); width as usize];
+        let mut rows = self.rows.borrow_mut();
+        if rows.len() <= row as usize {
+            rows.resize_with(row as usize + 1, || None);
+        }
+        let state = rows[row as usize].get_or_insert_with(|| self.bootstrap(seed, &mut contribution));
+        if state.chains.is_empty() {
+            return pixels;
+        }
+
+        let mutations = u64::from(width) * u64::from(spp);
+        let count = state.chains.len() as u64;
+        for (i, chain) in state.chains.iter_mut().enumerate() {
+            let mut n = mutations / count;
+            if (i as u64) < mutations % count {
+                n += 1;
+            }
+            for _ in 0..n {
+                chain.sampler.start_iteration();
+                let (proposed_x, proposed) = contribution(&mut chain.sampler);
+                let proposed_y = luminance(&proposed).max(0.0);
+                if chain.sampler.large_step {
+                    state.luminance += f64::from(proposed_y);
+                    state.paths += 1;
+                }
+                let accept = if chain.y > 0.0 {
+                    (proposed_y / chain.y).min(1.0)
+                } else {
+                    1.0
+                };
+
+                // Splat both states by their expected share, which lowers
+                // variance over splatting only the one the chain moves to
+                if accept > 0.0 && proposed_y > 0.0 {
+                    splat(&mut pixels, proposed_x, accept / proposed_y * proposed);
+                }
+                if chain.y > 0.0 {
+                    splat(&mut pixels, chain.x, (1.0 - accept) / chain.y * chain.color);
+                }
+
+                if state.rng.gen::<f32>() < accept {
+                    chain.x = proposed_x;
+                    chain.color = proposed;
+                    chain.y = proposed_y;
+                    chain.sampler.accept();
+                } else {
+                    chain.sampler.reject();
+                }
+            }
+        }
+
+        let scale = (state.luminance / state.paths as f64) as f32 / spp as f32;
+        for p in pixels.iter_mut() {
+            *p *= scale;
+        }
+        pixels
+    }
+
+    /// Traces a row's bootstrap paths, whose mean luminance starts the
+    /// running mean, and starts each chain from one of them picked in
+    /// proportion to its luminance.
+    fn bootstrap<F>(&self, seed: u64, contribution: &mut F) -> Row
+    where
+        F: FnMut(&mut dyn Sampler) -> (f32, Vec3),
+    {
+        let base = mix(seed);
+        let seed = |index: u32| mix(base ^ u64::from(index));
+        let weights: Vec<f32> = (0..self.bootstrap)
+            .map(|i| {
+                let mut sampler = MltSampler::new(seed(i), self.sigma, self.large_step);
+                luminance(&contribution(&mut sampler).1).max(0.0)
+            })
+            .collect();
+        let total: f32 = weights.iter().sum();
+        let mut rng = StdRng::seed_from_u64(seed(u32::MAX));
+
+        let mut chains = Vec::new();
+        if total > 0.0 {
+            for _ in 0..self.chains {
+                let mut pick = rng.gen::<f32>() * total;
+                let mut index = weights.len() - 1;
+                for (i, w) in weights.iter().enumerate() {
+                    if pick < *w {
+                        index = i;
+                        break;
+                    }
+                    pick -= w;
+                }
+                // Replayed from its seed, then free to go its own way
+                let mut sampler = MltSampler::new(seed(index as u32), self.sigma, self.large_step);
+                let (x, color) = contribution(&mut sampler);
+                sampler.reseed(rng.gen());
+                chains.push(Chain { sampler, x, color, y: luminance(&color).max(0.0) });
+            }
+        }
+        Row {
+            chains,
+            rng,
+            luminance: f64::from(total),
+            paths: u64::from(self.bootstrap),
+        }
+    }
+}
+
+fn splat(pixels: &mut [Vec3], x: f32, value: Vec3) {
+    let i = (x.max(0.0) as usize).min(pixels.len() - 1);
+    pixels[i] += value;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MltSampler;
+    use crate::hitable::HitList;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::sampler::Sampler;
+    use crate::scene::Scene;
+    use crate::settings::{IntegratorKind, RenderSettings};
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn rejected_mutation_restores_sample() {
+        let mut sampler = MltSampler::new(7, 0.01, 0.0);
+        let before: Vec<f32> = (0..4).map(|_| sampler.next_1d()).collect();
+        sampler.accept();
+        sampler.start_iteration();
+        let moved: Vec<f32> = (0..4).map(|_| sampler.next_1d()).collect();
+        assert_ne!(before, moved);
+        sampler.reject();
+        sampler.start_iteration();
+        sampler.reject();
+        // A replay with no pending mutation sees the accepted values
+        let mut replay = MltSampler::new(7, 0.01, 0.0);
+        let again: Vec<f32> = (0..4).map(|_| replay.next_1d()).collect();
+        assert_eq!(before, again);
+        for (i, x) in sampler.x.iter().enumerate() {
+            assert_eq!(x.value, before[i]);
+        }
+    }
+
+    #[test]
+    fn matches_path_tracer() {
+        let (width, height) = (32, 16);
+        let render = |integrator: IntegratorKind| {
+            let mut world = HitList { list: Vec::new() };
+            world.list.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::Lambertian { mat: Lambertian::new(0.5, 0.5, 0.5) }));
+            world.list.push(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, Material::Lambertian { mat: Lambertian::new(0.8, 0.3, 0.3) }));
+            world.list.push(Sphere::new(Vec3::new(3.0, 4.0, 1.0), 0.5, Material::DiffuseLight { mat: DiffuseLight::new(40.0, 40.0, 40.0) }));
+            let settings = RenderSettings { integrator, ..RenderSettings::default() };
+            let mut scene = Scene::from_world(width, height, world, settings);
+            for _ in 0..2048 {
+                scene.render_pass();
+            }
+            scene.aov("beauty").unwrap()
+        };
+        let mlt = render(IntegratorKind::Mlt);
+        let path = render(IntegratorKind::Path);
+        // Chains normalized by the wrong mean, or drawn back to where they
+        // started on every pass, leave parts of the picture too bright or
+        // too dark
+        let block = |image: &[f32], bx: u32, by: u32| -> f32 {
+            let mut sum = 0.0;
+            for y in by * 8..by * 8 + 8 {
+                for x in bx * 8..bx * 8 + 8 {
+                    let i = ((y * width + x) * 3) as usize;
+                    sum += image[i] + image[i + 1] + image[i + 2];
+                }
+            }
+            sum / 192.0
+        };
+        for by in 0..2 {
+            for bx in 0..4 {
+                let (a, b) = (block(&mlt, bx, by), block(&path, bx, by));
+                assert!((a - b).abs() < 0.02 * b, "block {} {}: {} vs {}", bx, by, a, b);
+            }
+        }
+    }
+}
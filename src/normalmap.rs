@@ -0,0 +1,120 @@
+use crate::hitable::HitRecord;
+use crate::onb::Onb;
+use crate::texture::Texture;
+use crate::vec3::Vec3;
+use serde::{Serialize, Deserialize};
+
+/// Perturbs the shading normal of a surface without changing its geometry.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum NormalMap {
+    /// Tangent-space normals encoded as RGB in `[0, 1]`, with blue along the
+    /// surface normal.
+    Tangent { texture: Texture },
+    /// Scalar height field, in scene units once multiplied by `scale`.
+    Bump { height: Texture, scale: f32 },
+}
+
+/// Step in texture space for the bump map's finite differences.
+const BUMP_DELTA: f32 = 1.0 / 1024.0;
+
+impl NormalMap {
+    pub fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        let n = rec.normal;
+        let normal = match self {
+            NormalMap::Tangent { texture } => {
+                let c = 2.0 * texture.value(rec.u, rec.v, &rec.p) - Vec3::new(1.0, 1.0, 1.0);
+                // dpdu needn't be perpendicular to the shading normal, and
+                // vanishes at a sphere's poles
+                let t = rec.dpdu - n.dot(&rec.dpdu) * n;
+                let t = if t.squared_length() > 1e-12 {
+                    t.unit()
+                } else {
+                    Onb::from_w(&n).to_world(&Vec3::new(1.0, 0.0, 0.0))
+                };
+                let b = n.cross(&t);
+                c.x() * t + c.y() * b + c.z() * n
+            }
+            NormalMap::Bump { height, scale } => {
+                let h = |u: f32, v: f32| scale * height.mask(u, v, &rec.p);
+                let h0 = h(rec.u, rec.v);
+                let dhdu = (h(rec.u + BUMP_DELTA, rec.v) - h0) / BUMP_DELTA;
+                let dhdv = (h(rec.u, rec.v + BUMP_DELTA) - h0) / BUMP_DELTA;
+                let dpdu = rec.dpdu + dhdu * n;
+                let dpdv = rec.dpdv + dhdv * n;
+                let bumped = dpdu.cross(&dpdv);
+                if bumped.dot(&n) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                }
+            }
+        };
+        if normal.squared_length() == 0.0 || normal.x().is_nan() {
+            return n;
+        }
+        normal.unit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NormalMap, Vec3};
+    use crate::hitable::HitRecord;
+    use crate::material::{Lambertian, Material};
+    use crate::texture::Texture;
+
+    #[test]
+    fn flat_maps_leave_normal_alone() {
+        let m = Material::Lambertian {
+            mat: Lambertian::new(0.5, 0.5, 0.5),
+        };
+        let rec = HitRecord::facing_z(&m);
+        let tangent = NormalMap::Tangent {
+            texture: Texture::Constant {
+                color: Vec3::new(0.5, 0.5, 1.0),
+            },
+        };
+        let bump = NormalMap::Bump {
+            height: Texture::Constant {
+                color: Vec3::new(0.3, 0.3, 0.3),
+            },
+            scale: 1.0,
+        };
+        assert!((tangent.shading_normal(&rec) - rec.normal).length() < 1e-6);
+        assert!((bump.shading_normal(&rec) - rec.normal).length() < 1e-6);
+    }
+
+    #[test]
+    fn tangent_map_tilts_along_tangent() {
+        let m = Material::Lambertian {
+            mat: Lambertian::new(0.5, 0.5, 0.5),
+        };
+        let rec = HitRecord::facing_z(&m);
+        let tangent = NormalMap::Tangent {
+            texture: Texture::Constant {
+                color: Vec3::new(1.0, 0.5, 0.5),
+            },
+        };
+        assert!((tangent.shading_normal(&rec) - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn tangent_frame_is_orthonormal() {
+        let m = Material::Lambertian {
+            mat: Lambertian::new(0.5, 0.5, 0.5),
+        };
+        let tangent = NormalMap::Tangent {
+            texture: Texture::Constant {
+                color: Vec3::new(1.0, 0.5, 0.5),
+            },
+        };
+        // A tangent leaning out of the surface is straightened first
+        let mut rec = HitRecord::facing_z(&m);
+        rec.dpdu = Vec3::new(2.0, 0.0, 3.0);
+        assert!((tangent.shading_normal(&rec) - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-6);
+        // With no tangent at all any direction in the surface will do
+        rec.dpdu = Vec3::new(0.0, 0.0, 0.0);
+        let normal = tangent.shading_normal(&rec);
+        assert!((normal.length() - 1.0).abs() < 1e-6 && normal.z().abs() < 1e-6);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::vec3::Vec3;
+
+/// Orthonormal basis used as a local shading frame, with `w` along the normal.
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    pub fn from_w(n: &Vec3) -> Onb {
+        let w = n.unit();
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = w.cross(&a).unit();
+        let u = w.cross(&v);
+        Onb { u, v, w }
+    }
+
+    pub fn to_world(&self, a: &Vec3) -> Vec3 {
+        a.x() * self.u + a.y() * self.v + a.z() * self.w
+    }
+
+    pub fn to_local(&self, a: &Vec3) -> Vec3 {
+        Vec3::new(a.dot(&self.u), a.dot(&self.v), a.dot(&self.w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Onb;
+    use super::Vec3;
+
+    #[test]
+    fn round_trip() {
+        let onb = Onb::from_w(&Vec3::new(0.0, 2.0, 0.0));
+        let a = Vec3::new(0.3, -0.4, 0.5);
+        let b = onb.to_world(&onb.to_local(&a));
+        assert!((a - b).length() < 1e-6);
+        assert_eq!(onb.to_local(&Vec3::new(0.0, 1.0, 0.0)).z(), 1.0);
+    }
+}
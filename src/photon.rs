@@ -0,0 +1,499 @@
+use crate::camera::random_in_unit_disk;
+use crate::hitable::{HitList, HitRecord, Hitable};
+use crate::integrator::{background, leaks, light_pdf, power_heuristic, sample_light, shading_frame};
+use crate::kdtree::{KdTree, Point};
+use crate::material::{Bsdf, Subsurface};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::{mix, Independent, Sampler};
+use crate::settings::RenderSettings;
+use crate::spectrum::{ColorMode, Spectrum};
+use crate::vec3::{random_cosine_direction, random_unit_vector, Vec3};
+use std::f32::consts::PI;
+
+/// How quickly progressive passes shrink the gather radius, from Knaus and
+/// Zwicker's probabilistic formulation.
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Light arriving at a diffuse surface.
+pub struct Photon {
+    p: Vec3,
+    /// Direction back towards where the photon came from.
+    wi: Vec3,
+    power: Vec3,
+}
+
+impl Point for Photon {
+    fn position(&self) -> Vec3 {
+        self.p
+    }
+}
+
+/// Photon maps from one round of shooting and the radius they are read with.
+struct PhotonPass {
+    global: KdTree<Photon>,
+    caustic: KdTree<Photon>,
+    radius: f32,
+}
+
+/// Photon mapper. Camera paths follow specular bounces to the first diffuse
+/// surface, which takes direct light from light sampling, caustics from the
+/// caustic map, and everything else from the global map one gather bounce
+/// away.
+///
+/// The progressive variant shoots a fresh pass before every round of
+/// samples, with a radius that shrinks from one pass to the next. The film
+/// averages the rounds, so the blur and noise of the estimate both vanish
+/// as passes are added. Passes are seeded by their number, so separate
+/// renderers of the same scene, such as workers sharing out rows, shoot
+/// the same maps.
+pub struct PhotonMapper {
+    max_depth: u32,
+    rr_depth: u32,
+    photons: u32,
+    caustic_photons: u32,
+    gather: usize,
+    /// Radius of the next pass.
+    radius: f32,
+    progressive: bool,
+    /// Progressive passes shot so far.
+    shot: u32,
+    pass: Option<PhotonPass>,
+}
+
+impl PhotonMapper {
+    pub fn new(settings: &RenderSettings, progressive: bool) -> PhotonMapper {
+        PhotonMapper {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+            photons: settings.photons,
+            caustic_photons: settings.caustic_photons,
+            gather: settings.photon_gather as usize,
+            radius: settings.photon_radius,
+            progressive,
+            shot: 0,
+            pass: None,
+        }
+    }
+
+    /// Shoots the photon maps, unless progressive, whose passes come from
+    /// `next_pass`. Photons come from the lights and from the sky; the sky's
+    /// photons are spread over the `focus` sphere, and caustic photons are
+    /// aimed at the specular `targets`.
+    pub fn preprocess<T: Hitable>(
+        &mut self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+    ) {
+        if !self.progressive {
+            self.pass = Some(self.shoot_pass(world, lights, targets, focus));
+        }
+    }
+
+    /// Replaces the progressive pass with a fresh one read with a smaller
+    /// radius, for the next round of samples.
+    pub fn next_pass<T: Hitable>(
+        &mut self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+    ) {
+        if !self.progressive {
+            return;
+        }
+        self.pass = Some(self.shoot_pass(world, lights, targets, focus));
+        self.shot += 1;
+        let i = self.shot as f32;
+        self.radius *= ((i + ALPHA) / (i + 1.0)).sqrt();
+    }
+
+    /// Progressive passes shot so far and the radius the current pass is
+    /// read with.
+    #[cfg(test)]
+    pub fn progress(&self) -> (u32, Option<f32>) {
+        (self.shot, self.pass.as_ref().map(|pass| pass.radius))
+    }
+
+    fn shoot_pass<T: Hitable>(
+        &self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+    ) -> PhotonPass {
+        let global = self.shoot(world, lights, &[], focus, self.photons, false);
+        let caustic = if targets.is_empty() {
+            Vec::new()
+        } else {
+            self.shoot(world, lights, targets, focus, self.caustic_photons, true)
+        };
+        PhotonPass {
+            global: KdTree::new(global),
+            caustic: KdTree::new(caustic),
+            radius: self.radius,
+        }
+    }
+
+    fn shoot<T: Hitable>(
+        &self,
+        world: &HitList<T>,
+        lights: &[&T],
+        targets: &[&T],
+        focus: (Vec3, f32),
+        count: u32,
+        caustic: bool,
+    ) -> Vec<Photon> {
+        let mut sampler = Independent::seeded(mix(u64::from(self.shot) << 1 | u64::from(caustic)));
+        let mut photons = Vec::new();
+        for _ in 0..count {
+            if let Some((ray, power)) = emit(world, lights, targets, focus, &mut sampler) {
+                self.trace(world, ray, power / count as f32, caustic, &mut sampler, &mut photons);
+            }
+        }
+        photons
+    }
+
+    /// Follows a photon through the scene, storing it at diffuse surfaces.
+    /// Caustic photons are only stored after one or more specular bounces and
+    /// stop at the first diffuse surface.
+    fn trace<T: Hitable>(
+        &self,
+        world: &HitList<T>,
+        r: Ray,
+        power: Vec3,
+        caustic: bool,
+        sampler: &mut dyn Sampler,
+        photons: &mut Vec<Photon>,
+    ) {
+        let mut ray = r;
+        let mut power = power;
+        let mut medium: Option<&Subsurface> = None;
+        let mut specular_path = true;
+
+        for depth in 0..self.max_depth {
+            let mut x = match world.hit(&ray, 0.001, f32::MAX) {
+                Some(x) => x,
+                None => break,
+            };
+
+            if let Some(m) = medium {
+                let length = ray.direction().length();
+                let flight = m.sample_free_flight(x.t * length, sampler);
+                power *= flight.weight;
+                if flight.scattered {
+                    if caustic {
+                        break;
+                    }
+                    specular_path = false;
+                    let p = ray.point_at_parameter(flight.distance / length);
+                    ray = Ray::new(p, m.sample_phase(sampler));
+                    continue;
+                }
+            }
+
+            let (uvw, wo) = shading_frame(&mut x, &ray);
+            if x.material.is_diffuse() {
+                if !caustic || (specular_path && depth > 0) {
+                    photons.push(Photon {
+                        p: x.p,
+                        wi: -ray.direction().unit(),
+                        power,
+                    });
+                }
+                if caustic {
+                    break;
+                }
+                specular_path = false;
+            }
+
+            let s = match x.material.sample(&x, &wo, sampler) {
+                Some(s) => s,
+                None => break,
+            };
+            let direction = uvw.to_world(&s.wi);
+            if leaks(&x, &-ray.direction(), &direction, &wo, &s.wi) {
+                break;
+            }
+            let weight = s.weight();
+            if depth >= self.rr_depth {
+                // Keep the photon's power steady by absorbing instead of dimming
+                let survive = weight.max_value().min(1.0);
+                if survive <= 0.0 || sampler.next_1d() >= survive {
+                    break;
+                }
+                power *= weight / survive;
+            } else {
+                power *= weight;
+            }
+            medium = if direction.dot(&x.geometric_normal) < 0.0 {
+                x.material.medium()
+            } else {
+                None
+            };
+            ray = Ray::new(x.p, direction);
+        }
+    }
+
+    /// Radiance leaving a diffuse hit towards `wo`, estimated from the photons
+    /// around it.
+    fn estimate(&self, map: &KdTree<Photon>, radius: f32, x: &HitRecord, uvw: &Onb, wo: &Vec3) -> Vec3 {
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        let mut add = |photon: &Photon| {
+            let wi = uvw.to_local(&photon.wi);
+            sum += x.material.eval(x, wo, &wi) * photon.power;
+        };
+        let max_radius_squared = radius * radius;
+        let radius_squared = if self.progressive {
+            map.within(&x.p, max_radius_squared, |photon, _| add(photon));
+            max_radius_squared
+        } else {
+            let (found, radius_squared) = map.nearest(&x.p, self.gather, max_radius_squared);
+            for photon in &found {
+                add(photon);
+            }
+            if found.len() < self.gather {
+                max_radius_squared
+            } else {
+                radius_squared
+            }
+        };
+        if radius_squared <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        sum / (PI * radius_squared)
+    }
+
+    pub fn li<T: Hitable, C: ColorMode>(
+        &self,
+        r: Ray,
+        world: &HitList<T>,
+        lights: &[&T],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+    ) -> C::Value {
+        let mut radiance = mode.constant(0.0);
+        let pass = match &self.pass {
+            Some(pass) => pass,
+            None => return radiance,
+        };
+
+        let mut throughput = mode.constant(1.0);
+        let mut ray = r;
+        let mut medium: Option<&Subsurface> = None;
+        let mut specular_bounce = true;
+        let mut bsdf_pdf = 0.0;
+        // Set once the path has left its first diffuse surface; light that
+        // reaches that surface through specular bounces alone is a caustic
+        // and comes from the caustic map instead
+        let mut gathering = false;
+        let mut caustic = false;
+
+        for depth in 0.. {
+            let mut x = match world.hit(&ray, 0.001, f32::MAX) {
+                Some(x) => x,
+                None => {
+                    if !caustic {
+                        radiance += throughput * mode.rgb(background(&ray));
+                    }
+                    break;
+                }
+            };
+
+            if let Some(m) = medium {
+                let length = ray.direction().length();
+                let flight = m.sample_free_flight(x.t * length, sampler);
+                throughput = throughput * mode.rgb(flight.weight);
+                if flight.scattered {
+                    if depth >= self.max_depth {
+                        break;
+                    }
+                    let p = ray.point_at_parameter(flight.distance / length);
+                    ray = Ray::new(p, m.sample_phase(sampler));
+                    specular_bounce = true;
+                    continue;
+                }
+            }
+
+            x.wavelength = mode.wavelength();
+            let (uvw, wo) = shading_frame(&mut x, &ray);
+
+            let emitted = x.material.emitted(&x);
+            if emitted.max_value() > 0.0 && !caustic {
+                let weight = if specular_bounce {
+                    1.0
+                } else {
+                    power_heuristic(bsdf_pdf, light_pdf(lights, &ray.origin(), &ray.direction()))
+                };
+                radiance += throughput * mode.rgb(emitted) * weight;
+            }
+
+            if depth >= self.max_depth {
+                break;
+            }
+
+            let diffuse = x.material.is_diffuse();
+            if diffuse {
+                if gathering {
+                    let global = self.estimate(&pass.global, pass.radius, &x, &uvw, &wo);
+                    radiance += throughput * mode.rgb(global);
+                    break;
+                }
+                if !lights.is_empty() {
+                    radiance += throughput * sample_light(world, lights, &x, &uvw, &wo, mode, sampler);
+                }
+                let caustics = self.estimate(&pass.caustic, pass.radius, &x, &uvw, &wo);
+                radiance += throughput * mode.rgb(caustics);
+            }
+
+            let s = match x.material.sample(&x, &wo, sampler) {
+                Some(s) => s,
+                None => break,
+            };
+            let direction = uvw.to_world(&s.wi);
+            if leaks(&x, &-ray.direction(), &direction, &wo, &s.wi) {
+                break;
+            }
+            if s.dispersive {
+                mode.terminate_secondary();
+            }
+            // A specular lobe of a diffuse surface, such as a clear coat,
+            // isn't covered by the estimates above and is followed instead
+            if diffuse && !s.specular {
+                gathering = true;
+            } else if gathering && s.specular {
+                caustic = true;
+            }
+            throughput = throughput * mode.rgb(s.weight());
+            specular_bounce = s.specular;
+            bsdf_pdf = s.pdf;
+            medium = if direction.dot(&x.geometric_normal) < 0.0 {
+                x.material.medium()
+            } else {
+                None
+            };
+            ray = Ray::new(x.p, direction);
+        }
+        radiance
+    }
+}
+
+/// Starts a photon at a random light or the sky, returning its ray and power.
+fn emit<T: Hitable>(
+    world: &HitList<T>,
+    lights: &[&T],
+    targets: &[&T],
+    focus: (Vec3, f32),
+    sampler: &mut dyn Sampler,
+) -> Option<(Ray, Vec3)> {
+    let sources = lights.len() + 1;
+    let index = ((sampler.next_1d() * sources as f32) as usize).min(sources - 1);
+    let pick_pdf = 1.0 / sources as f32;
+
+    if index < lights.len() {
+        let (rec, pdf_pos) = lights[index].sample_surface(sampler)?;
+        let n = rec.geometric_normal;
+        let (direction, pdf_dir) = if targets.is_empty() {
+            let local = random_cosine_direction(sampler);
+            (Onb::from_w(&n).to_world(&local), local.z() / PI)
+        } else {
+            let target = targets[(sampler.next_1d() * targets.len() as f32) as usize % targets.len()];
+            let direction = target.random(&rec.p, sampler).unit();
+            (direction, light_pdf(targets, &rec.p, &direction))
+        };
+        let cos = n.dot(&direction);
+        if cos <= 0.0 || pdf_pos <= 0.0 || pdf_dir <= 0.0 {
+            return None;
+        }
+        let le = rec.material.emitted(&rec);
+        return Some((Ray::new(rec.p, direction), le * (cos / (pick_pdf * pdf_pos * pdf_dir))));
+    }
+
+    // Sky photons arrive along parallel rays through a disk facing them,
+    // covering either the focus region or one of the targets
+    let direction = random_unit_vector(sampler);
+    let pdf_dir = 1.0 / (4.0 * PI);
+    let (center, radius) = if targets.is_empty() {
+        focus
+    } else {
+        let target = targets[(sampler.next_1d() * targets.len() as f32) as usize % targets.len()];
+        target.bounding_sphere()?
+    };
+    let uvw = Onb::from_w(&direction);
+    let disk = random_in_unit_disk(sampler);
+    // Start outside everything, so the sky can't light what it doesn't reach
+    let (world_center, world_radius) = world.bounding_sphere()?;
+    let distance = (center - world_center).length() + world_radius + radius;
+    let origin = center + radius * uvw.to_world(&disk) - distance * direction;
+    let pdf_pos = if targets.is_empty() {
+        1.0 / (PI * radius * radius)
+    } else {
+        // Any target whose disk the ray also crosses could have produced it
+        targets
+            .iter()
+            .filter_map(|t| t.bounding_sphere())
+            .filter(|(c, r)| {
+                let oc = *c - origin;
+                (oc - oc.dot(&direction) * direction).squared_length() < r * r
+            })
+            .map(|(_, r)| 1.0 / (PI * r * r))
+            .sum::<f32>()
+            / targets.len() as f32
+    };
+    if pdf_pos <= 0.0 {
+        return None;
+    }
+    let le = background(&Ray::new(origin, -direction));
+    Some((Ray::new(origin, direction), le / (pick_pdf * pdf_pos * pdf_dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PhotonMapper;
+    use crate::hitable::HitList;
+    use crate::integrator::PathTracer;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::ray::Ray;
+    use crate::sampler::Independent;
+    use crate::settings::RenderSettings;
+    use crate::spectrum::Rgb;
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn progressive_passes_converge_to_path_tracing() {
+        // A ball over a floor lit by a small light beside it, all inside a
+        // room that keeps the sky out. The underside of the ball only sees
+        // light off the floor, which comes from the photon map.
+        let mut world = HitList { list: Vec::new() };
+        let grey = |a: f32| Material::Lambertian { mat: Lambertian::new(a, a, a) };
+        world.list.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, grey(0.8)));
+        world.list.push(Sphere::new(Vec3::new(0.0, 1.5, 0.0), 0.5, grey(0.8)));
+        world.list.push(Sphere::new(Vec3::new(1.0, 0.5, 0.0), 0.1, Material::DiffuseLight { mat: DiffuseLight::new(100.0, 100.0, 100.0) }));
+        world.list.push(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 6.0, grey(0.5)));
+        let lights = [&world.list[2]];
+        let from = Vec3::new(0.0, 0.2, 3.0);
+        let r = Ray::new(from, Vec3::new(0.0, 1.3, 0.0) - from);
+        let mut sampler = Independent::new();
+
+        let settings = RenderSettings { photons: 10_000, photon_radius: 2.0, ..RenderSettings::default() };
+        let path = PathTracer::new(&settings);
+        let n = 100_000;
+        let reference = (0..n).map(|_| path.li(r, &world, &lights, &mut Rgb, &mut sampler).x()).sum::<f32>() / n as f32;
+
+        // A wide radius blurs the floor's bright spot, and shrinking it
+        // pass by pass takes the blur away
+        let mut ppm = PhotonMapper::new(&settings, true);
+        let mut estimates = Vec::new();
+        for _ in 0..64 {
+            ppm.next_pass(&world, &lights, &[], (Vec3::new(0.0, 1.0, 0.0), 3.0));
+            estimates.push((0..1000).map(|_| ppm.li(r, &world, &lights, &mut Rgb, &mut sampler).x()).sum::<f32>() / 1000.0);
+        }
+        let error = |passes: usize| estimates[..passes].iter().sum::<f32>() / passes as f32 / reference - 1.0;
+        let (early, late) = (error(4), error(64));
+        assert!(early < -0.025, "{}", early);
+        assert!(late.abs() < 0.5 * early.abs() && late.abs() < 0.03, "{} then {}", early, late);
+    }
+}
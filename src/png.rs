@@ -0,0 +1,193 @@
+//! PNG writer for finished, display-encoded images, at 8 or 16 bits per
+//! channel, with `tEXt` chunks recording how the image was made.
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+/// Encodes 8-bit RGBA, rows from the top. Fails if the pixels don't fill
+/// the image exactly or a text chunk breaks the PNG rules.
+pub fn encode_rgba8(width: u32, height: u32, rgba: &[u8], text: &[(&str, &str)]) -> Result<Vec<u8>, String> {
+    encode(width, height, 6, 8, 4, rgba, text)
+}
+
+/// Encodes 16-bit RGB, rows from the top, failing like `encode_rgba8`.
+pub fn encode_rgb16(width: u32, height: u32, rgb: &[u16], text: &[(&str, &str)]) -> Result<Vec<u8>, String> {
+    let bytes: Vec<u8> = rgb.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect();
+    encode(width, height, 2, 16, 6, &bytes, text)
+}
+
+fn encode(
+    width: u32,
+    height: u32,
+    color_type: u8,
+    bit_depth: u8,
+    bpp: usize,
+    pixels: &[u8],
+    text: &[(&str, &str)],
+) -> Result<Vec<u8>, String> {
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(format!("PNG size {}x{} out of range", width, height));
+    }
+    let expected = width as usize * height as usize * bpp;
+    if pixels.len() != expected {
+        return Err(format!(
+            "{}x{} PNG needs {} bytes of pixels, got {}",
+            width,
+            height,
+            expected,
+            pixels.len()
+        ));
+    }
+    for (keyword, value) in text {
+        // Keywords are 1 to 79 bytes, and a null would end either field
+        let length = latin1(keyword).len();
+        if length == 0 || length > 79 || keyword.contains('\0') || value.contains('\0') {
+            return Err(format!("invalid PNG text chunk {:?}", keyword));
+        }
+    }
+
+    let mut out = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Compression, filter and interlace methods are all the defaults
+    ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+    chunk(&mut out, b"IHDR", &ihdr);
+
+    for (keyword, value) in text {
+        let mut data = latin1(keyword);
+        data.push(0);
+        data.extend(latin1(value));
+        chunk(&mut out, b"tEXt", &data);
+    }
+
+    let stride = width as usize * bpp;
+    let mut filtered = Vec::with_capacity((stride + 1) * height as usize);
+    let mut previous = vec![0; stride];
+    for row in pixels.chunks(stride) {
+        filter_row(row, &previous, bpp, &mut filtered);
+        previous.copy_from_slice(row);
+    }
+    chunk(&mut out, b"IDAT", &compress_to_vec_zlib(&filtered, 6));
+    chunk(&mut out, b"IEND", &[]);
+    Ok(out)
+}
+
+/// Appends `row` with whichever of the five PNG filters gives the smallest
+/// sum of absolute differences, the usual heuristic for what deflates best.
+fn filter_row(row: &[u8], previous: &[u8], bpp: usize, out: &mut Vec<u8>) {
+    let mut best: Option<(u64, u8, Vec<u8>)> = None;
+    for filter in 0..5u8 {
+        let line: Vec<u8> = (0..row.len())
+            .map(|i| {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let b = previous[i];
+                let c = if i >= bpp { previous[i - bpp] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                row[i].wrapping_sub(predicted)
+            })
+            .collect();
+        let cost = line.iter().map(|&v| u64::from((v as i8).unsigned_abs())).sum();
+        if best.as_ref().is_none_or(|(lowest, _, _)| cost < *lowest) {
+            best = Some((cost, filter, line));
+        }
+    }
+    let (_, filter, line) = best.unwrap();
+    out.push(filter);
+    out.extend_from_slice(&line);
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = i16::from(a) + i16::from(b) - i16::from(c);
+    let (pa, pb, pc) = ((p - i16::from(a)).abs(), (p - i16::from(b)).abs(), (p - i16::from(c)).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Text chunks are Latin-1; anything outside it becomes `?`.
+fn latin1(s: &str) -> Vec<u8> {
+    s.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }).collect()
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    !bytes
+        .iter()
+        .fold(!0u32, |c, &b| table[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, encode_rgb16, encode_rgba8};
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    #[test]
+    fn chunks_and_filters_round_trip() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+
+        let rgba: Vec<u8> = (0..3 * 2 * 4).map(|i| (i * 37 % 251) as u8).collect();
+        let png = encode_rgba8(3, 2, &rgba, &[("Software", "rust_raytracer")]).unwrap();
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[24..26], &[8, 6]);
+        assert_eq!(&png[33 + 4..33 + 8], b"tEXt");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        // Undo whatever filter each row chose
+        let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+        let len = u32::from_be_bytes([png[idat - 4], png[idat - 3], png[idat - 2], png[idat - 1]]) as usize;
+        let filtered = decompress_to_vec_zlib(&png[idat + 4..idat + 4 + len]).unwrap();
+        let mut decoded: Vec<u8> = Vec::new();
+        for (y, line) in filtered.chunks(13).enumerate() {
+            for i in 0..12 {
+                let a = if i >= 4 { decoded[y * 12 + i - 4] } else { 0 };
+                let b = if y > 0 { decoded[(y - 1) * 12 + i] } else { 0 };
+                let c = if y > 0 && i >= 4 { decoded[(y - 1) * 12 + i - 4] } else { 0 };
+                let predicted = match line[0] {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((u16::from(a) + u16::from(b)) / 2) as u8,
+                    _ => super::paeth(a, b, c),
+                };
+                decoded.push(line[1 + i].wrapping_add(predicted));
+            }
+        }
+        assert_eq!(decoded, rgba);
+
+        let png = encode_rgb16(1, 1, &[0x1234, 0, 0xffff], &[]).unwrap();
+        assert_eq!(&png[24..26], &[16, 2]);
+
+        assert!(encode_rgba8(3, 2, &rgba[1..], &[]).is_err());
+        assert!(encode_rgba8(0, 2, &[], &[]).is_err());
+        assert!(encode_rgba8(3, 2, &rgba, &[("", "empty keyword")]).is_err());
+        let long = "k".repeat(80);
+        assert!(encode_rgba8(3, 2, &rgba, &[(long.as_str(), "")]).is_err());
+    }
+}
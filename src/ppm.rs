@@ -0,0 +1,39 @@
+//! Netpbm writers: binary PPM for display images and PFM for linear HDR.
+
+/// Encodes 8-bit RGBA, rows from the top, as a binary PPM. Alpha is dropped.
+pub fn encode_ppm(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for p in rgba.chunks(4).take((width * height) as usize) {
+        out.extend_from_slice(&p[..3]);
+    }
+    out
+}
+
+/// Encodes linear RGB, three floats per pixel with rows from the top, as a
+/// little-endian PFM. PFM stores rows from the bottom.
+pub fn encode_pfm(width: u32, height: u32, rgb: &[f32]) -> Vec<u8> {
+    let mut out = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    let stride = width as usize * 3;
+    for row in rgb.chunks(stride).take(height as usize).rev() {
+        for v in row {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_pfm, encode_ppm};
+
+    #[test]
+    fn headers_and_row_order() {
+        let ppm = encode_ppm(2, 1, &[1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(ppm, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06".to_vec());
+
+        let pfm = encode_pfm(1, 2, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&pfm[..header.len()], &header[..]);
+        assert_eq!(&pfm[header.len()..header.len() + 4], &4.0f32.to_le_bytes());
+    }
+}
@@ -0,0 +1,551 @@
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Serialize, Deserialize};
+use std::sync::OnceLock;
+
+/// Source of the random numbers a sample is built from. Each call hands out
+/// the next dimension of the current sample, uniform in [0, 1).
+///
+/// Dimensions are used in a fixed order: the position in the pixel, then
+/// the lens, then whatever the integrator asks for at each bounce (BSDF,
+/// light and roulette samples). Samplers that spread a pixel's samples
+/// evenly do so dimension by dimension, and need `start_pixel_sample`
+/// before each sample.
+pub trait Sampler {
+    fn next_1d(&mut self) -> f32;
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let u = self.next_1d();
+        (u, self.next_1d())
+    }
+
+    /// Moves to sample `index` of pixel `(x, y)`, back at its first
+    /// dimension. Samplers without structure across samples ignore it.
+    fn start_pixel_sample(&mut self, _x: u32, _y: u32, _index: u32) {}
+}
+
+/// Which sampler camera paths draw their random numbers from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerKind {
+    Independent,
+    /// Jittered strata, shuffled independently in every dimension.
+    Stratified,
+    /// Halton points, rotated per pixel.
+    Halton,
+    /// Owen-scrambled Sobol points, shuffled per dimension.
+    Sobol,
+    /// Per-pixel offsets from a blue-noise mask, so that at a few samples
+    /// per pixel the error looks like fine grain rather than blotches.
+    BlueNoise,
+}
+
+/// Makes a sampler of the given kind, stratifying rounds of `spp` samples
+/// per pixel. Only the stratified sampler needs to know.
+pub fn make(kind: SamplerKind, spp: u32) -> Box<dyn Sampler> {
+    match kind {
+        SamplerKind::Independent => Box::new(Independent::new()),
+        SamplerKind::Stratified => Box::new(Stratified::new(spp)),
+        SamplerKind::Halton => Box::new(Halton::default()),
+        SamplerKind::Sobol => Box::new(Sobol::default()),
+        SamplerKind::BlueNoise => Box::new(BlueNoise::default()),
+    }
+}
+
+/// Independent uniform random numbers with no structure between samples.
+pub struct Independent {
+    rng: StdRng,
+}
+
+impl Independent {
+    pub fn new() -> Independent {
+        Independent { rng: StdRng::from_rng(thread_rng()).unwrap() }
+    }
+
+    /// The same numbers every time for the same `seed`.
+    pub fn seeded(seed: u64) -> Independent {
+        Independent { rng: StdRng::seed_from_u64(seed) }
+    }
+}
+
+impl Default for Independent {
+    fn default() -> Independent {
+        Independent::new()
+    }
+}
+
+impl Sampler for Independent {
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+/// Which sample of which pixel is being drawn, and how many dimensions of
+/// it have been used.
+#[derive(Clone, Copy, Debug, Default)]
+struct Cursor {
+    x: u32,
+    y: u32,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+impl Cursor {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.x = x;
+        self.y = y;
+        self.pixel = mix(u64::from(x) << 32 | u64::from(y));
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// Hash of the pixel, the current dimension and `salt`, the same for
+    /// every sample of the pixel.
+    fn seed(&self, salt: u32) -> u32 {
+        mix(self.pixel ^ mix(u64::from(self.dimension) << 32 | u64::from(salt))) as u32
+    }
+
+    /// Uniform number unique to this sample, dimension and `salt`.
+    fn uniform(&self, salt: u32) -> f32 {
+        to_unit(self.seed(salt ^ self.index.wrapping_mul(0x9e37_79b9)))
+    }
+
+    fn advance(&mut self, dimensions: u32) {
+        self.dimension += dimensions;
+    }
+}
+
+/// Jittered stratification: the `spp` samples of a pixel each fall in a
+/// different stratum of every dimension, and in a different cell of a grid
+/// for 2D requests. Strata are shuffled separately per pixel and dimension
+/// so the dimensions don't correlate. Later rounds of `spp` samples are
+/// stratified again among themselves.
+pub struct Stratified {
+    spp: u32,
+    cursor: Cursor,
+}
+
+impl Stratified {
+    pub fn new(spp: u32) -> Stratified {
+        Stratified {
+            spp: spp.max(1),
+            cursor: Cursor::default(),
+        }
+    }
+}
+
+impl Sampler for Stratified {
+    fn next_1d(&mut self) -> f32 {
+        let round = self.cursor.index / self.spp;
+        let stratum = permute(self.cursor.index % self.spp, self.spp, self.cursor.seed(round));
+        let u = (stratum as f32 + self.cursor.uniform(0)) / self.spp as f32;
+        self.cursor.advance(1);
+        u.min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let nx = (self.spp as f32).sqrt().ceil() as u32;
+        let ny = self.spp.div_ceil(nx);
+        let round = self.cursor.index / self.spp;
+        let cell = permute(self.cursor.index % self.spp, nx * ny, self.cursor.seed(round));
+        let u = ((cell % nx) as f32 + self.cursor.uniform(0)) / nx as f32;
+        let v = ((cell / nx) as f32 + self.cursor.uniform(1)) / ny as f32;
+        self.cursor.advance(2);
+        (u.min(ONE_MINUS_EPSILON), v.min(ONE_MINUS_EPSILON))
+    }
+
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.cursor.start(x, y, index);
+    }
+}
+
+/// The Halton sequence, one prime base per dimension, with a random
+/// Cranley-Patterson rotation per pixel and dimension so neighbouring
+/// pixels don't repeat each other's pattern. Dimensions past the last
+/// prime fall back to independent numbers.
+#[derive(Default)]
+pub struct Halton {
+    cursor: Cursor,
+}
+
+impl Sampler for Halton {
+    fn next_1d(&mut self) -> f32 {
+        let u = match PRIMES.get(self.cursor.dimension as usize) {
+            Some(&base) => {
+                let rotated = radical_inverse(base, self.cursor.index) + f64::from(self.cursor.seed(0)) / 4_294_967_296.0;
+                (rotated.fract() as f32).min(ONE_MINUS_EPSILON)
+            }
+            None => self.cursor.uniform(0),
+        };
+        self.cursor.advance(1);
+        u
+    }
+
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.cursor.start(x, y, index);
+    }
+}
+
+/// Owen-scrambled Sobol points after Burley's "Practical Hash-based Owen
+/// Scrambling": every request uses the first one or two Sobol dimensions,
+/// with the sample order shuffled per pixel and dimension and the points
+/// scrambled by hashing. Each 1D and 2D request is then well stratified on
+/// its own for any power of two samples, without a table of direction
+/// numbers.
+#[derive(Default)]
+pub struct Sobol {
+    cursor: Cursor,
+}
+
+impl Sobol {
+    fn shuffled_index(&self) -> u32 {
+        nested_uniform_scramble(self.cursor.index, self.cursor.seed(0))
+    }
+}
+
+impl Sampler for Sobol {
+    fn next_1d(&mut self) -> f32 {
+        let x = nested_uniform_scramble(sobol_0(self.shuffled_index()), self.cursor.seed(1));
+        self.cursor.advance(1);
+        to_unit(x)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let i = self.shuffled_index();
+        let x = nested_uniform_scramble(sobol_0(i), self.cursor.seed(1));
+        let y = nested_uniform_scramble(sobol_1(i), self.cursor.seed(2));
+        self.cursor.advance(2);
+        (to_unit(x), to_unit(y))
+    }
+
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.cursor.start(x, y, index);
+    }
+}
+
+/// Blue-noise dithered sampling (Georgiev and Fajardo): each pixel's
+/// samples follow the same low-discrepancy sequence, R1 for 1D requests and
+/// R2 for 2D ones, rotated by the value of a tiled blue-noise mask at the
+/// pixel. Neighbouring pixels then get dissimilar offsets, which pushes the
+/// error into high frequencies where it reads as fine grain. Every dimension
+/// reads the mask at its own toroidal shift so dimensions don't correlate.
+#[derive(Default)]
+pub struct BlueNoise {
+    cursor: Cursor,
+}
+
+impl BlueNoise {
+    /// The mask's value at this pixel for the current dimension and `salt`.
+    /// The shift leaves out the pixel, so neighbouring pixels read
+    /// neighbouring cells and keep the mask's spectrum.
+    fn offset(&self, salt: u32) -> f32 {
+        let shift = mix(u64::from(self.cursor.dimension) << 32 | u64::from(salt)) as u32;
+        let x = (self.cursor.x + (shift & 0xffff)) % MASK_SIZE;
+        let y = (self.cursor.y + (shift >> 16)) % MASK_SIZE;
+        blue_noise_mask()[(y * MASK_SIZE + x) as usize]
+    }
+}
+
+impl Sampler for BlueNoise {
+    fn next_1d(&mut self) -> f32 {
+        let alpha = 0.618_034;
+        let u = self.offset(0) + alpha * (self.cursor.index % 4096) as f32;
+        self.cursor.advance(1);
+        u.fract().min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let (a1, a2) = (0.754_877_7, 0.569_840_3);
+        let i = (self.cursor.index % 4096) as f32;
+        let u = self.offset(0) + a1 * i;
+        let v = self.offset(1) + a2 * i;
+        self.cursor.advance(2);
+        (u.fract().min(ONE_MINUS_EPSILON), v.fract().min(ONE_MINUS_EPSILON))
+    }
+
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.cursor.start(x, y, index);
+    }
+}
+
+/// Side of the tiled blue-noise mask.
+const MASK_SIZE: u32 = 64;
+
+/// Blue-noise mask made once by Ulichney's void-and-cluster method: every
+/// cell holds its rank in the order cells were added, over the cell count,
+/// so any threshold of it is an evenly spread point set.
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(MASK_SIZE as usize, 1.5))
+}
+
+fn void_and_cluster(size: usize, sigma: f32) -> Vec<f32> {
+    let n = size * size;
+    // Gaussian splat of one point, on the torus
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(i % size), wrap(i / size));
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let mut energy = vec![0.0f32; n];
+    let mut set = vec![false; n];
+    let toggle = |energy: &mut [f32], set: &mut [bool], p: usize| {
+        set[p] = !set[p];
+        let sign = if set[p] { 1.0 } else { -1.0 };
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let (dx, dy) = ((q % size + size - px) % size, (q / size + size - py) % size);
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // Tightest cluster is the set cell with most energy, largest void the
+    // empty cell with least
+    let tightest = |energy: &[f32], set: &[bool]| {
+        (0..n).filter(|&i| set[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |energy: &[f32], set: &[bool]| {
+        (0..n).filter(|&i| !set[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // A random tenth of the cells, relaxed until moving the tightest
+    // cluster into the largest void changes nothing
+    let initial = n / 10;
+    let mut placed = 0;
+    let mut seed = 0u64;
+    while placed < initial {
+        seed += 1;
+        let p = (mix(seed) % n as u64) as usize;
+        if !set[p] {
+            toggle(&mut energy, &mut set, p);
+            placed += 1;
+        }
+    }
+    for _ in 0..n {
+        let cluster = tightest(&energy, &set);
+        toggle(&mut energy, &mut set, cluster);
+        let void = largest_void(&energy, &set);
+        toggle(&mut energy, &mut set, void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0usize; n];
+    // Ranks below the initial points come from taking them out again,
+    // tightest first
+    let (mut e, mut s) = (energy.clone(), set.clone());
+    for r in (0..initial).rev() {
+        let cluster = tightest(&e, &s);
+        toggle(&mut e, &mut s, cluster);
+        rank[cluster] = r;
+    }
+    // The rest fill the largest voids in turn. With energy from the set
+    // cells, the empty cell with the least is also the tightest cluster of
+    // empty cells, so this covers Ulichney's last phase too
+    for r in initial..n {
+        let void = largest_void(&energy, &set);
+        toggle(&mut energy, &mut set, void);
+        rank[void] = r;
+    }
+    rank.iter().map(|&r| r as f32 / n as f32).collect()
+}
+
+/// SplitMix64 finaliser, so nearby seeds give unrelated random streams.
+pub fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Largest f32 below 1.
+const ONE_MINUS_EPSILON: f32 = 0.999_999_94;
+
+/// Top 24 bits of `x` as a number in [0, 1).
+fn to_unit(x: u32) -> f32 {
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109,
+    113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229, 233, 239,
+    241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+/// `index` with its digits in `base` mirrored about the radix point.
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inv = 1.0 / f64::from(base);
+    let (mut reversed, mut scale) = (0.0, inv);
+    while index > 0 {
+        reversed += f64::from(index % base) * scale;
+        index /= base;
+        scale *= inv;
+    }
+    reversed
+}
+
+/// First Sobol dimension, the base 2 van der Corput sequence, as 32 bits.
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+/// Second Sobol dimension, whose direction numbers each come from the last
+/// by `v ^ v >> 1`.
+fn sobol_1(mut index: u32) -> u32 {
+    let (mut x, mut v) = (0, 1u32 << 31);
+    while index != 0 {
+        if index & 1 == 1 {
+            x ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    x
+}
+
+/// Owen scrambling of the bits of `x` from the most significant down, each
+/// flipped depending on the ones above it, using Burley's hash.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut v = x.reverse_bits();
+    v = v.wrapping_add(seed);
+    v ^= v.wrapping_mul(0x6c50_b47c);
+    v ^= v.wrapping_mul(0xb82f_1e52);
+    v ^= v.wrapping_mul(0xc7af_e638);
+    v ^= v.wrapping_mul(0x8d22_f6e6);
+    v.reverse_bits()
+}
+
+/// Kensler's hashed permutation: where `i` goes in a shuffle of `0..len`
+/// chosen by `seed`, found without storing the shuffle.
+fn permute(mut i: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blue_noise_mask, make, Halton, Sampler, SamplerKind, MASK_SIZE};
+
+    /// Cells of an `n` by `n` grid hit by `spp` 2D samples from the pixel's
+    /// `pair`th pair, starting at sample `first`.
+    fn cells_hit(sampler: &mut dyn Sampler, first: u32, spp: u32, n: u32, pair: u32) -> usize {
+        let mut hit = vec![false; (n * n) as usize];
+        for i in first..first + spp {
+            sampler.start_pixel_sample(3, 7, i);
+            for _ in 0..pair {
+                sampler.next_2d();
+            }
+            let (u, v) = sampler.next_2d();
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+            hit[((v * n as f32) as u32 * n + (u * n as f32) as u32) as usize] = true;
+        }
+        hit.iter().filter(|h| **h).count()
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_stratify() {
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol].iter() {
+            // Every pair of dimensions, not just the first, fills the grid
+            for pair in 0..4 {
+                let mut sampler = make(*kind, 16);
+                assert_eq!(cells_hit(sampler.as_mut(), 0, 16, 4, pair), 16, "{:?} pair {}", kind, pair);
+            }
+        }
+
+        // Stratified sized for an adaptive minimum stratifies that many
+        // samples, then each later round of as many again
+        let mut stratified = make(SamplerKind::Stratified, 16);
+        for round in 0..3 {
+            assert_eq!(cells_hit(stratified.as_mut(), round * 16, 16, 4, 1), 16, "round {}", round);
+        }
+
+        // The first eight base 2 points, however rotated, fill eight strata
+        let mut halton = Halton::default();
+        let mut hit = [false; 8];
+        for i in 0..8 {
+            halton.start_pixel_sample(5, 1, i);
+            hit[(halton.next_1d() * 8.0) as usize] = true;
+        }
+        assert!(hit.iter().all(|h| *h));
+    }
+
+    #[test]
+    fn blue_noise_mask_is_even_at_every_scale() {
+        let mask = blue_noise_mask();
+        let n = (MASK_SIZE * MASK_SIZE) as usize;
+        let mut ranks: Vec<usize> = mask.iter().map(|v| (v * n as f32).round() as usize).collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+
+        // Each 8x8 block averages close to a half, far closer than the
+        // +-0.04 or so of white noise
+        for by in 0..8 {
+            for bx in 0..8 {
+                let mut sum = 0.0;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        sum += mask[((by * 8 + y) * MASK_SIZE + bx * 8 + x) as usize];
+                    }
+                }
+                assert!((sum / 64.0 - 0.5).abs() < 0.03, "block {} {}: {}", bx, by, sum / 64.0);
+            }
+        }
+
+        // The first sample of neighbouring pixels is a window of the mask,
+        // which is what keeps their error blue
+        let mut sampler = make(SamplerKind::BlueNoise, 4);
+        let mut first = [0.0; 16 * 16];
+        for y in 0..16 {
+            for x in 0..16 {
+                sampler.start_pixel_sample(x + 40, y + 20, 0);
+                first[(y * 16 + x) as usize] = sampler.next_1d();
+            }
+        }
+        let window = (0..MASK_SIZE * MASK_SIZE).find(|start| {
+            let (sx, sy) = (start % MASK_SIZE, start / MASK_SIZE);
+            (0..16 * 16).all(|i| {
+                let (x, y) = ((sx + i % 16) % MASK_SIZE, (sy + i / 16) % MASK_SIZE);
+                first[i as usize] == mask[(y * MASK_SIZE + x) as usize]
+            })
+        });
+        assert!(window.is_some());
+
+        for i in 0..16 {
+            sampler.start_pixel_sample(i * 37, i * 11, i);
+            let (u, v) = sampler.next_2d();
+            let w = sampler.next_1d();
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) && (0.0..1.0).contains(&w));
+        }
+    }
+}
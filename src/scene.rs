@@ -1,30 +1,310 @@
-use rand::prelude::*;
-
+use crate::aov::{Aov, AovPixel, AovSample, Framebuffer};
 use crate::camera::Camera;
+use crate::denoise::{self, Guides};
+use crate::exr::{self, Compression, PixelType};
+use crate::debug::heat;
+use crate::film::{quantize, Film, LightSplat, Splats, Welford};
+use crate::hdr;
+use crate::png;
+use crate::ppm;
+use crate::tonemap;
 use crate::hitable::HitList;
+use crate::integrator::{Integrator, LightPaths};
+use crate::ray::Ray;
+use crate::sampler::{self, Independent, Sampler};
 use crate::sphere::Sphere;
+use crate::settings::{DenoiseSettings, DisplaySettings, RenderSettings};
+use crate::spectrum::{luminance, ColorMode, Rgb, SampledWavelengths};
 use crate::vec3::Vec3;
-use crate::color;
+use crate::utils::set_panic_hook;
 
 use wasm_bindgen::prelude::*;
 
+/// How many new samples one pixel took, and its statistics afterwards when
+/// sampling adaptively.
+type PixelSamples = (u32, Option<Welford>);
+
 #[wasm_bindgen]
 pub struct Scene {
     width: u32,
     height: u32,
     cam: Camera,
     world: HitList<Sphere>,
+    /// Indices of the emissive spheres in `world`.
+    lights: Vec<usize>,
+    /// Sphere around what the camera sees.
+    focus: (Vec3, f32),
+    settings: RenderSettings,
+    integrator: Integrator,
+    /// Running sums of linear radiance for every row rendered so far.
+    film: Film,
+    /// The requested AOVs for every row rendered so far.
+    framebuffer: Framebuffer,
+    /// Running AOV sums behind `framebuffer`, one per pixel when any AOVs
+    /// were asked for.
+    aov_pixels: Vec<AovPixel>,
+    /// Luminance statistics of every pixel, indexed like `aov_pixels`, when
+    /// sampling adaptively.
+    stats: Vec<Welford>,
+    /// Progressive passes rendered so far.
+    passes: u32,
+    /// Rows `image_row` has rendered since the integrator's last pass.
+    swept: Vec<bool>,
 }
 
 #[wasm_bindgen]
 impl Scene {
-    pub fn new(width: u32, height: u32, world_obj: JsValue) -> Scene {
+    pub fn new(width: u32, height: u32, world_obj: JsValue) -> Result<Scene, JsValue> {
+        Scene::with_settings(width, height, world_obj, JsValue::UNDEFINED)
+    }
+
+    /// Throws if the world or the settings aren't valid.
+    #[allow(deprecated)]
+    pub fn with_settings(width: u32, height: u32, world_obj: JsValue, settings: JsValue) -> Result<Scene, JsValue> {
+        let world: HitList<Sphere> = world_obj
+            .into_serde()
+            .map_err(|e| JsValue::from_str(&format!("invalid world: {}", e)))?;
+        let settings: RenderSettings = if settings.is_undefined() || settings.is_null() {
+            RenderSettings::default()
+        } else {
+            settings
+                .into_serde()
+                .map_err(|e| JsValue::from_str(&format!("invalid render settings: {}", e)))?
+        };
+        Ok(Scene::from_world(width, height, world, settings))
+    }
+
+    /// Renders row `y` into the film and returns it quantised for display.
+    /// Rendering a row again adds to the samples it already has. Every pixel
+    /// gets 100 samples, or with adaptive sampling as many as it needs. A
+    /// filter wider than a pixel also spreads samples into the rows around
+    /// `y`, so a row is only final once its neighbours have been rendered.
+    /// Progressive photon mapping shoots one photon pass per sweep over the
+    /// image: rows share it until a row comes round again.
+    pub fn image_row(&mut self, y: u32) -> Vec<u8> {
+        set_panic_hook();
+        if !self.swept.contains(&true) || self.swept.get(y as usize) == Some(&true) {
+            self.next_pass();
+        }
+        if let Some(swept) = self.swept.get_mut(y as usize) {
+            *swept = true;
+        }
+        self.accumulate_row(y, self.pixel_budget());
+        self.display_row(y)
+    }
+
+    /// Adds one sample to every pixel and returns the whole image so far as
+    /// 8-bit RGBA, rows from the top. Calling it repeatedly refines the
+    /// image, which is usable after any pass. With adaptive sampling,
+    /// pixels that have converged are skipped. Progressive photon mapping
+    /// shoots a fresh photon pass each time, with a smaller radius.
+    pub fn render_pass(&mut self) -> Vec<u8> {
+        set_panic_hook();
+        self.next_pass();
+        for y in 0..self.height {
+            self.accumulate_row(y, 1);
+        }
+        self.passes += 1;
+        self.display()
+    }
+
+    /// Progressive passes rendered so far.
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    /// The whole accumulated image as 8-bit RGBA, rows from the top.
+    pub fn display(&self) -> Vec<u8> {
+        (0..self.height).rev().flat_map(|y| self.display_row(y)).collect()
+    }
+
+    /// Row `y` of the accumulated image as 8-bit RGBA, without rendering
+    /// anything new.
+    pub fn display_row(&self, y: u32) -> Vec<u8> {
+        (0..self.width)
+            .flat_map(|x| quantize(self.display_color(x, y)).to_vec())
+            .collect()
+    }
+
+    /// Replaces the exposure and tone mapping. `display` and `display_row`
+    /// show the change straight away, without re-tracing. Throws if the
+    /// settings aren't valid.
+    #[allow(deprecated)]
+    pub fn set_display(&mut self, display: JsValue) -> Result<(), JsValue> {
+        let display: DisplaySettings = display
+            .into_serde()
+            .map_err(|e| JsValue::from_str(&format!("invalid display settings: {}", e)))?;
+        self.settings.display = display;
+        Ok(())
+    }
+
+    /// The HDR accumulation buffer: per pixel, the running RGB sum of
+    /// filter-weighted linear radiance, the sum of the weights, the samples
+    /// taken there and the RGB sum of light splatted onto it by light
+    /// paths. Rows run from the top.
+    pub fn accumulation(&self) -> Vec<f32> {
+        self.film.accumulation()
+    }
+
+    /// Adds the accumulation buffer of another scene rendering the same
+    /// image, so a filter wider than a pixel blends rows rendered by
+    /// different workers and light paths from every worker reach every row.
+    /// `display`, the sample heatmap and the image metadata then cover the
+    /// combined image.
+    pub fn add_accumulation(&mut self, accumulation: &[f32]) {
+        self.film.add_accumulation(accumulation);
+    }
+
+    /// Names of the buffers this scene fills, beauty first.
+    #[allow(deprecated)]
+    pub fn aov_names(&self) -> JsValue {
+        let names: Vec<&str> = std::iter::once(Aov::Beauty)
+            .chain(self.framebuffer.aovs())
+            .map(|aov| aov.name())
+            .collect();
+        JsValue::from_serde(&names).unwrap()
+    }
+
+    /// Floats per pixel in the named buffer, zero for an unknown name.
+    pub fn aov_channels(&self, name: &str) -> u32 {
+        Aov::from_name(name).map_or(0, |aov| aov.channels() as u32)
+    }
+
+    /// Whole named buffer, rows from the top. Only rows this scene rendered
+    /// are filled, so with several workers each holds its own rows.
+    pub fn aov(&self, name: &str) -> Option<Vec<f32>> {
+        match Aov::from_name(name)? {
+            Aov::Beauty => Some(self.film.mean()),
+            aov => self.framebuffer.get(aov).map(|data| data.to_vec()),
+        }
+    }
+
+    /// Row `y` of the named buffer, numbered like `image_row`.
+    pub fn aov_row(&self, name: &str, y: u32) -> Option<Vec<f32>> {
+        match Aov::from_name(name)? {
+            Aov::Beauty if y < self.height => Some(
+                (0..self.width)
+                    .flat_map(|x| {
+                        let c = self.film.pixel(x, y);
+                        vec![c.r(), c.g(), c.b()]
+                    })
+                    .collect(),
+            ),
+            Aov::Beauty => None,
+            aov => self.framebuffer.row(aov, y).map(|data| data.to_vec()),
+        }
+    }
+
+    /// The displayed image as a PNG, 16 bits per channel if `sixteen_bit`
+    /// is set, otherwise 8-bit RGBA. The render settings and sample counts
+    /// are stored in text chunks.
+    pub fn png_image(&self, sixteen_bit: bool) -> Vec<u8> {
+        let info = self.render_info();
+        let text: Vec<(&str, &str)> = info.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        if sixteen_bit {
+            let rgb: Vec<u16> = (0..self.height)
+                .rev()
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| {
+                    let c = self.display_color(x, y);
+                    vec![c.r(), c.g(), c.b()]
+                })
+                .map(|v| (v * 65535.0).round() as u16)
+                .collect();
+            png::encode_rgb16(self.width, self.height, &rgb, &text)
+        } else {
+            png::encode_rgba8(self.width, self.height, &self.display(), &text)
+        }
+        .expect("encoding the displayed image")
+    }
+
+    /// The displayed image as a binary PPM.
+    pub fn ppm_image(&self) -> Vec<u8> {
+        ppm::encode_ppm(self.width, self.height, &self.display())
+    }
+
+    /// The accumulated beauty pass as linear floats in a PFM file.
+    pub fn pfm_image(&self) -> Vec<u8> {
+        ppm::encode_pfm(self.width, self.height, &self.film.mean())
+    }
+
+    /// The accumulated image after denoising, as 8-bit RGBA with rows from
+    /// the top like `display`. `settings` overrides the scene's denoise
+    /// settings if given. The film itself is left as it is, so more passes
+    /// can be added and denoised again. Throws if the settings aren't
+    /// valid.
+    #[allow(deprecated)]
+    pub fn denoise(&self, settings: JsValue) -> Result<Vec<u8>, JsValue> {
+        let settings: DenoiseSettings = if settings.is_undefined() || settings.is_null() {
+            self.settings.denoise.clone()
+        } else {
+            settings
+                .into_serde()
+                .map_err(|e| JsValue::from_str(&format!("invalid denoise settings: {}", e)))?
+        };
+        Ok(self
+            .denoised(&settings)
+            .iter()
+            .flat_map(|c| quantize(self.to_display(*c)).to_vec())
+            .collect())
+    }
+
+    /// Samples taken in each pixel as a blue to red heatmap, 8-bit RGBA
+    /// with rows from the top. Red is the adaptive sampling limit, or the
+    /// busiest pixel without adaptive sampling.
+    pub fn sample_heatmap(&self) -> Vec<u8> {
+        let counts = self.film.sample_counts();
+        let max = if self.settings.adaptive {
+            self.settings.adaptive_max_spp as usize
+        } else {
+            counts.iter().cloned().fold(0.0, f32::max) as usize
+        };
+        counts.iter().flat_map(|n| quantize(heat(*n as u32, max)).to_vec()).collect()
+    }
+
+    /// The accumulated beauty pass as a Radiance `.hdr` file.
+    pub fn hdr_image(&self) -> Vec<u8> {
+        hdr::encode(self.width, self.height, &self.film.mean())
+    }
+
+    /// The accumulated beauty pass as an OpenEXR file, with every AOV in
+    /// the framebuffer as a layer of named channels. Channels are half
+    /// floats unless `half` is false, and ZIP compressed if `zip` is set.
+    /// Object IDs and sample counts are always full floats, since halves
+    /// lose whole numbers above 2048.
+    pub fn exr_image(&self, half: bool, zip: bool) -> Vec<u8> {
+        let pixel_type = if half { PixelType::Half } else { PixelType::Float };
+        let beauty = self.film.mean();
+        let mut layers = vec![exr::Layer {
+            name: "",
+            channels: Aov::Beauty.channel_names(),
+            data: &beauty,
+            pixel_type,
+        }];
+        for aov in self.framebuffer.aovs() {
+            layers.push(exr::Layer {
+                name: aov.name(),
+                channels: aov.channel_names(),
+                data: self.framebuffer.get(aov).unwrap(),
+                pixel_type: match aov {
+                    Aov::ObjectId | Aov::SampleCount => PixelType::Float,
+                    _ => pixel_type,
+                },
+            });
+        }
+        let compression = if zip { Compression::Zip } else { Compression::None };
+        exr::encode(self.width, self.height, &layers, compression)
+    }
+}
+
+impl Scene {
+    /// Builds a scene from an already deserialised world, for native use.
+    pub fn from_world(width: u32, height: u32, world: HitList<Sphere>, settings: RenderSettings) -> Scene {
         let lookfrom = Vec3::new(16.0, 2.0, 4.0);
         let lookat = Vec3::new(0.0, 0.0, 0.0);
         let vup = Vec3::new(0.0, 1.0, 0.0);
         let dist_to_focus: f32 = (lookfrom - lookat).length();
         let aperture: f32 = 0.2;
-        let world: HitList<Sphere> = world_obj.into_serde().unwrap();
 
         let cam: Camera = Camera::new(
             lookfrom,
@@ -36,38 +316,401 @@ impl Scene {
             dist_to_focus,
         );
 
+        let lights: Vec<usize> = (0..world.list.len())
+            .filter(|&i| world.list[i].material().is_emissive())
+            .collect();
+
+        let focus = (lookat, dist_to_focus);
+        let mut integrator = Integrator::new(&settings, &cam);
+        {
+            let (emitters, targets) = photon_sources(&world, &lights);
+            integrator.preprocess(&world, &emitters, &targets, focus);
+        }
+
+        let film = Film::new(width, height);
+        // Light path AOVs the integrator can't split would only hold zeros
+        let aovs: Vec<Aov> = settings
+            .aovs
+            .iter()
+            .copied()
+            .filter(|aov| integrator.splits_paths() || !aov.is_light_path())
+            .collect();
+        let framebuffer = Framebuffer::new(width, height, &aovs);
+        let aov_pixels = if framebuffer.aovs().next().is_some() {
+            (0..width * height).map(|_| AovPixel::new()).collect()
+        } else {
+            Vec::new()
+        };
+        let stats = if settings.adaptive {
+            vec![Welford::default(); (width * height) as usize]
+        } else {
+            Vec::new()
+        };
         Scene {
             width,
             height,
             cam,
-            world
+            world,
+            lights,
+            focus,
+            settings,
+            integrator,
+            film,
+            framebuffer,
+            aov_pixels,
+            stats,
+            passes: 0,
+            swept: vec![false; height as usize],
         }
     }
 
-    pub fn image_row(&self, y: u32) -> Vec<u8> {
-        console_error_panic_hook::set_once();
-        (0..self.width)
-            .into_iter()
-            .map(|x| {
-                let mut rng = thread_rng();
-                let mut col = Vec3::new(0.0, 0.0, 0.0);
-                let ns = 100;
-                for _ in 0..ns {
-                    let pu: f32 = rng.gen();
-                    let pv: f32 = rng.gen();
-                    let u: f32 = (x as f32 + pu) / self.width as f32;
-                    let v: f32 = (y as f32 + pv) / self.height as f32;
-                    let r = self.cam.get_ray(u, v);
-                    col += color(r, &self.world, 0);
+    /// Prepares the integrator for another round of samples.
+    fn next_pass(&mut self) {
+        self.swept.iter_mut().for_each(|swept| *swept = false);
+        let (emitters, targets) = photon_sources(&self.world, &self.lights);
+        self.integrator.next_pass(&self.world, &emitters, &targets, self.focus);
+    }
+
+    /// The accumulated radiance run through the denoiser, rows from the top.
+    pub fn denoised(&self, settings: &DenoiseSettings) -> Vec<Vec3> {
+        let color: Vec<Vec3> = (0..self.height)
+            .rev()
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.film.pixel(x, y))
+            .collect();
+        denoise::denoise(self.width, self.height, &color, &self.guides(), settings)
+    }
+
+    /// Albedo, normal and depth for the denoiser, rows from the top. They
+    /// come from the framebuffer when those AOVs were rendered, otherwise
+    /// from one camera ray through each pixel centre.
+    fn guides(&self) -> Guides {
+        let stored = |aov: Aov| self.framebuffer.get(aov);
+        let traced: Vec<AovSample> = if [Aov::Albedo, Aov::Normal, Aov::Depth].iter().all(|aov| stored(*aov).is_some()) {
+            Vec::new()
+        } else {
+            let mut sampler = Independent::new();
+            (0..self.height)
+                .rev()
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let u = (x as f32 + 0.5) / self.width as f32;
+                    let v = (y as f32 + 0.5) / self.height as f32;
+                    self.surface(&self.cam.get_ray(u, v, &mut sampler))
+                })
+                .collect()
+        };
+        let vectors = |aov: Aov, field: fn(&AovSample) -> Vec3| -> Vec<Vec3> {
+            match stored(aov) {
+                Some(data) => data.chunks(3).map(|c| Vec3::new(c[0], c[1], c[2])).collect(),
+                None => traced.iter().map(field).collect(),
+            }
+        };
+        Guides {
+            albedo: vectors(Aov::Albedo, |s| s.albedo),
+            normal: vectors(Aov::Normal, |s| s.normal),
+            depth: match stored(Aov::Depth) {
+                Some(data) => data.to_vec(),
+                None => traced.iter().map(|s| s.depth).collect(),
+            },
+        }
+    }
+
+    /// Display colour of pixel `(x, y)`, encoded and in [0, 1].
+    fn display_color(&self, x: u32, y: u32) -> Vec3 {
+        self.to_display(self.film.pixel(x, y))
+    }
+
+    /// Encodes linear radiance for display, in [0, 1].
+    fn to_display(&self, col: Vec3) -> Vec3 {
+        // Debug views are already display colours
+        if self.integrator.is_diagnostic() {
+            Vec3::new(col.r().clamp(0.0, 1.0), col.g().clamp(0.0, 1.0), col.b().clamp(0.0, 1.0))
+        } else {
+            tonemap::display_value(col, &self.settings.display)
+        }
+    }
+
+    /// Text describing how the image was made, for image metadata.
+    fn render_info(&self) -> Vec<(&'static str, String)> {
+        let samples: f32 = self.film.sample_counts().iter().sum();
+        let pixels = (self.width * self.height).max(1) as f32;
+        vec![
+            ("Software", "rust_raytracer".to_string()),
+            ("Settings", serde_json::to_string(&self.settings).unwrap()),
+            (
+                "Render stats",
+                format!(
+                    "{}x{}, {} samples, {:.1} samples per pixel, {} progressive passes",
+                    self.width,
+                    self.height,
+                    samples,
+                    samples / pixels,
+                    self.passes
+                ),
+            ),
+        ]
+    }
+
+    /// Renders `ns` more samples per pixel of row `y` into the film and
+    /// the AOVs, or with adaptive sampling up to `ns` until each pixel
+    /// converges. Samples also land in the rows around `y` that the filter
+    /// reaches.
+    fn accumulate_row(&mut self, y: u32, ns: u32) {
+        let (splats, row, aovs) = self.render_row(y, ns);
+        self.film.add_splats(&splats);
+        for (x, (count, stats)) in row.into_iter().enumerate() {
+            self.film.count_samples(x as u32, y, count as f32);
+            if let Some(stats) = stats {
+                self.stats[(y * self.width) as usize + x] = stats;
+            }
+        }
+        let requested: Vec<Aov> = self.framebuffer.aovs().collect();
+        for (x, pixel) in aovs.iter().enumerate() {
+            let accumulated = &mut self.aov_pixels[(y * self.width) as usize + x];
+            accumulated.merge(pixel);
+            for aov in &requested {
+                self.framebuffer.set(*aov, x as u32, y, &accumulated.value(*aov));
+            }
+        }
+    }
+
+    /// Filter-weighted samples of row `y`, the sample count of each pixel
+    /// with its updated statistics when sampling adaptively, and the
+    /// pixels' AOVs when any were asked for. AOVs only go to the pixel each
+    /// sample was taken in. Metropolis light transport spreads samples over
+    /// the row as it pleases, so it always takes `ns` per pixel and keeps
+    /// them in their own pixel.
+    fn render_row(&self, y: u32, ns: u32) -> (Splats, Vec<PixelSamples>, Vec<AovPixel>) {
+        let lights: Vec<&Sphere> = self.lights.iter().map(|&i| &self.world.list[i]).collect();
+        let wants_aovs = !self.aov_pixels.is_empty();
+        let mut sampler = sampler::make(self.settings.sampler, self.strata());
+        let mut splats = Splats::new(self.width, y, self.settings.filter, self.settings.filter_radius);
+        let mut aovs: Vec<AovPixel> = Vec::new();
+
+        let row = match &self.integrator {
+            Integrator::Mlt(mlt) => {
+                // Chains wander over the whole row, so the surface AOVs come
+                // from a separate pass of camera rays
+                if wants_aovs {
+                    for x in 0..self.width {
+                        let mut pixel = AovPixel::new();
+                        for i in 0..ns {
+                            sampler.start_pixel_sample(x, y, self.film.samples(x, y) as u32 + i);
+                            let (pu, pv) = sampler.next_2d();
+                            let u: f32 = (x as f32 + pu) / self.width as f32;
+                            let v: f32 = (y as f32 + pv) / self.height as f32;
+                            pixel.add(&self.surface(&self.cam.get_ray(u, v, sampler.as_mut())));
+                        }
+                        aovs.push(pixel);
+                    }
                 }
-                col /= ns as f32;
-                col = Vec3::new(col.r().sqrt(), col.g().sqrt(), col.b().sqrt()); // Raise gamma to 2
-                let ir = (255.99 * col.r()) as u8;
-                let ig = (255.99 * col.g()) as u8;
-                let ib = (255.99 * col.b()) as u8;
-                vec![ir, ig, ib, 255]
-            })
-            .flatten()
-            .collect::<Vec<_>>()
+                // Samples already in the row tell renders of it apart
+                let seed = u64::from(y) | (self.film.samples(0, y) as u64) << 32;
+                mlt.render_row(y, self.width, seed, ns, |sampler| {
+                    let x = sampler.next_1d() * self.width as f32;
+                    let v = (y as f32 + sampler.next_1d()) / self.height as f32;
+                    (x, self.sample(x / self.width as f32, v, &lights, sampler, None, &mut Vec::new()))
+                })
+                .into_iter()
+                .enumerate()
+                .map(|(x, mean)| {
+                    splats.add_pixel(x as u32, mean * ns as f32, ns as f32);
+                    (ns, None)
+                })
+                .collect()
+            }
+            _ => (0..self.width)
+                .map(|x| {
+                    let mut pixel = AovPixel::new();
+                    let mut stats = self.stats.get((y * self.width + x) as usize).copied();
+                    let mut taken = 0;
+                    while taken < ns && !stats.is_some_and(|s| self.converged(&s)) {
+                        // Samples from earlier calls carry on the pixel's sequence
+                        sampler.start_pixel_sample(x, y, self.film.samples(x, y) as u32 + taken);
+                        let (pu, pv) = sampler.next_2d();
+                        let u: f32 = (x as f32 + pu) / self.width as f32;
+                        let v: f32 = (y as f32 + pv) / self.height as f32;
+                        let aov = if wants_aovs { Some(&mut pixel) } else { None };
+                        let mut light = Vec::new();
+                        let c = self.sample(u, v, &lights, sampler.as_mut(), aov, &mut light);
+                        for splat in light {
+                            splats.add_light(splat);
+                        }
+                        if let Some(stats) = stats.as_mut() {
+                            stats.add(luminance(&c));
+                        }
+                        splats.add(x as f32 + pu, y as f32 + pv, c);
+                        taken += 1;
+                    }
+                    if wants_aovs {
+                        aovs.push(pixel);
+                    }
+                    (taken, stats)
+                })
+                .collect(),
+        };
+        (splats, row, aovs)
+    }
+
+    /// Samples `image_row` gives each pixel, at most.
+    fn pixel_budget(&self) -> u32 {
+        if self.settings.adaptive {
+            self.settings.adaptive_max_spp
+        } else {
+            100
+        }
+    }
+
+    /// Samples per round of stratification. Adaptive pixels may stop once
+    /// they have their minimum, so that much is stratified on its own and
+    /// any further samples in later rounds.
+    fn strata(&self) -> u32 {
+        if self.settings.adaptive {
+            self.settings.adaptive_min_spp.max(1)
+        } else {
+            self.pixel_budget()
+        }
+    }
+
+    /// Whether adaptive sampling is done with a pixel.
+    fn converged(&self, stats: &Welford) -> bool {
+        let n = stats.count();
+        n >= self.settings.adaptive_max_spp
+            || (n >= self.settings.adaptive_min_spp && stats.relative_error() < self.settings.adaptive_threshold)
+    }
+
+    /// Color seen through film position `(u, v)`, drawing every random
+    /// number from `sampler`. Adds what the sample saw to `aovs` if given,
+    /// and light its light paths brought elsewhere on the film to `light`.
+    fn sample(
+        &self,
+        u: f32,
+        v: f32,
+        lights: &[&Sphere],
+        sampler: &mut dyn Sampler,
+        aovs: Option<&mut AovPixel>,
+        light: &mut Vec<LightSplat<Vec3>>,
+    ) -> Vec3 {
+        let r = self.cam.get_ray(u, v, sampler);
+        let mut surface = aovs.as_ref().map(|_| self.surface(&r));
+        let split = surface.is_some();
+        let (color, paths) = if self.settings.spectral {
+            let mut lambda = SampledWavelengths::sample_uniform(sampler.next_1d());
+            let mut splats = Vec::new();
+            let (radiance, paths) = self.trace(r, lights, &mut lambda, sampler, split, &mut splats);
+            light.extend(splats.into_iter().map(|s| LightSplat {
+                u: s.u,
+                v: s.v,
+                value: lambda.to_rgb(&s.value),
+            }));
+            (lambda.to_rgb(&radiance), paths.map(|p| p.map(|s| lambda.to_rgb(s))))
+        } else {
+            self.trace(r, lights, &mut Rgb, sampler, split, light)
+        };
+        if let (Some(pixel), Some(surface)) = (aovs, surface.as_mut()) {
+            surface.paths = paths;
+            pixel.add(surface);
+        }
+        color
+    }
+
+    fn trace<C: ColorMode>(
+        &self,
+        r: Ray,
+        lights: &[&Sphere],
+        mode: &mut C,
+        sampler: &mut dyn Sampler,
+        split: bool,
+        splats: &mut Vec<LightSplat<C::Value>>,
+    ) -> (C::Value, Option<LightPaths<C::Value>>) {
+        if split {
+            if let Some(paths) = self.integrator.li_paths(r, &self.world, lights, mode, sampler) {
+                return (paths.total(), Some(paths));
+            }
+        }
+        (self.integrator.li(r, &self.world, lights, mode, sampler, splats), None)
+    }
+
+    /// First hit of a camera ray, for the surface AOVs.
+    fn surface(&self, r: &Ray) -> AovSample {
+        match self.world.hit_index(r, 0.001, f32::MAX) {
+            Some((index, x)) => AovSample {
+                albedo: x.material.albedo(&x),
+                normal: x.material.shading_normal(&x),
+                depth: x.t * r.direction().length(),
+                position: x.p,
+                object_id: Some(index),
+                paths: None,
+            },
+            None => AovSample::background(),
+        }
+    }
+}
+
+/// The emitters among `lights`, and the specular spheres that caustic
+/// photons are aimed at.
+fn photon_sources<'a>(world: &'a HitList<Sphere>, lights: &[usize]) -> (Vec<&'a Sphere>, Vec<&'a Sphere>) {
+    let emitters = lights.iter().map(|&i| &world.list[i]).collect();
+    let targets = world
+        .list
+        .iter()
+        .filter(|s| !s.material().is_diffuse() && !s.material().is_emissive())
+        .collect();
+    (emitters, targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scene;
+    use crate::hitable::HitList;
+    use crate::integrator::Integrator;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::settings::{IntegratorKind, RenderSettings};
+    use crate::sphere::Sphere;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn rows_of_a_sweep_share_a_photon_pass() {
+        // The floor fills the bottom rows of the image
+        let build = || {
+            let mut world = HitList { list: Vec::new() };
+            world.list.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::Lambertian { mat: Lambertian::new(0.5, 0.5, 0.5) }));
+            world.list.push(Sphere::new(Vec3::new(0.0, 4.0, 0.0), 1.0, Material::DiffuseLight { mat: DiffuseLight::new(4.0, 4.0, 4.0) }));
+            let settings = RenderSettings {
+                integrator: IntegratorKind::ProgressivePhoton,
+                photons: 1000,
+                ..RenderSettings::default()
+            };
+            Scene::from_world(8, 8, world, settings)
+        };
+        let progress = |scene: &Scene| match &scene.integrator {
+            Integrator::Photon(ppm) => ppm.progress(),
+            _ => unreachable!(),
+        };
+
+        let mut scene = build();
+        scene.image_row(0);
+        let first = progress(&scene);
+        assert_eq!(first.0, 1);
+        scene.image_row(1);
+        assert_eq!(progress(&scene), first);
+
+        // Coming back to a row starts the next sweep, read with a smaller
+        // radius, and so does a progressive pass
+        scene.image_row(0);
+        let second = progress(&scene);
+        assert_eq!(second.0, 2);
+        assert!(second.1.unwrap() < first.1.unwrap());
+        scene.render_pass();
+        assert_eq!(progress(&scene).0, 3);
+        scene.image_row(1);
+        assert_eq!(progress(&scene).0, 4);
+
+        // Another renderer handed the next row, like a second worker, is on
+        // the same pass
+        let mut other = build();
+        other.image_row(1);
+        assert_eq!(progress(&other), first);
     }
 }
@@ -0,0 +1,205 @@
+use crate::aov::Aov;
+use crate::denoise::Denoiser;
+use crate::filter::Filter;
+use crate::sampler::SamplerKind;
+use crate::tonemap::ToneMapper;
+use serde::{Serialize, Deserialize};
+
+/// Per-render options passed from JS. Missing fields take their defaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// Trace sampled wavelengths instead of RGB so dispersive glass can split
+    /// light into a spectrum.
+    pub spectral: bool,
+    /// Longest path, in bounces.
+    pub max_depth: u32,
+    /// Bounce after which paths may be ended early by Russian roulette.
+    pub rr_depth: u32,
+    /// Light transport algorithm, by name.
+    pub integrator: IntegratorKind,
+    /// Where camera paths get their random numbers, by name.
+    pub sampler: SamplerKind,
+    /// How samples are weighted into the pixels around them, by name.
+    pub filter: Filter,
+    /// How far the filter reaches from a sample, in pixels.
+    pub filter_radius: f32,
+    /// Photons shot into the global photon map, per pass.
+    pub photons: u32,
+    /// Photons aimed at specular objects for the caustic map, per pass.
+    pub caustic_photons: u32,
+    /// Photons each photon map lookup gathers.
+    pub photon_gather: u32,
+    /// Largest lookup radius, and the starting radius of progressive photon
+    /// mapping.
+    pub photon_radius: f32,
+    /// Independent paths Metropolis light transport traces the first time it
+    /// renders a row, to normalize its chains by and start them from.
+    pub mlt_bootstrap: u32,
+    /// Markov chains per row for Metropolis light transport.
+    pub mlt_chains: u32,
+    /// Probability that a Metropolis mutation replaces the whole path rather
+    /// than perturbing it.
+    pub mlt_large_step: f32,
+    /// Standard deviation of a small step in primary sample space.
+    pub mlt_sigma: f32,
+    /// Brightest a path traced sample's light may be, in its brightest
+    /// channel, from emitters seen directly or light scattered once.
+    /// Brighter samples are scaled down, at the cost of some energy. Zero
+    /// leaves them alone.
+    pub clamp_direct: f32,
+    /// The same for light scattered more than once, where fireflies from
+    /// caustics come from.
+    pub clamp_indirect: f32,
+    /// Blur specular bounces after a path's first into a cone of directions,
+    /// so caustics are found by light sampling rather than by chance. It
+    /// biases the image, softening caustics and reflections of reflections.
+    pub regularize: bool,
+    /// Half-angle of the regularised cone, in degrees.
+    pub regularize_angle: f32,
+    /// Distance within which the ambient occlusion view counts a surface as
+    /// occluded.
+    pub ao_radius: f32,
+    /// Stop sampling each pixel once its estimated error is low enough,
+    /// instead of giving every pixel the same number of samples.
+    pub adaptive: bool,
+    /// Relative standard error below which a pixel counts as converged.
+    pub adaptive_threshold: f32,
+    /// Samples every pixel gets before its error estimate is trusted.
+    pub adaptive_min_spp: u32,
+    /// Most samples any pixel gets.
+    pub adaptive_max_spp: u32,
+    /// Extra buffers to fill alongside the beauty pass. The light path
+    /// buffers are left out for integrators that can't split the radiance.
+    pub aovs: Vec<Aov>,
+    /// How the HDR image is turned into display colours.
+    pub display: DisplaySettings,
+    /// Filter used by `Scene::denoise`.
+    pub denoise: DenoiseSettings,
+}
+
+/// Display transform applied to the accumulated HDR image. It can be changed
+/// after rendering without tracing anything again.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DisplaySettings {
+    /// Exposure adjustment in stops.
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    /// Radiance that extended Reinhard maps to white.
+    pub white_point: f32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> DisplaySettings {
+        DisplaySettings {
+            exposure: 0.0,
+            tone_mapper: ToneMapper::Clamp,
+            white_point: 4.0,
+        }
+    }
+}
+
+/// Options for denoising the accumulated image.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DenoiseSettings {
+    pub method: Denoiser,
+    /// À-trous passes. Each doubles the filter's reach.
+    pub iterations: u32,
+    /// Luminance differences, in local standard deviations, that the
+    /// à-trous filter smooths across.
+    pub sigma_luminance: f32,
+    /// Exponent on the cosine between neighbouring normals.
+    pub sigma_normal: f32,
+    /// Tolerance for depth differences beyond what the local slope predicts.
+    pub sigma_depth: f32,
+    /// Half-width of the non-local means search window, in pixels.
+    pub nlm_radius: u32,
+    /// Half-width of the patches non-local means compares.
+    pub nlm_patch: u32,
+    /// Non-local means strength: larger values average more.
+    pub nlm_strength: f32,
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> DenoiseSettings {
+        DenoiseSettings {
+            method: Denoiser::Atrous,
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_depth: 1.0,
+            nlm_radius: 5,
+            nlm_patch: 2,
+            nlm_strength: 0.45,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegratorKind {
+    /// Unidirectional path tracing with next event estimation.
+    Path,
+    /// Bidirectional path tracing.
+    Bdpt,
+    /// Photon mapping with caustic and global maps.
+    Photon,
+    /// Progressive photon mapping.
+    #[serde(rename = "ppm")]
+    ProgressivePhoton,
+    /// Primary sample space Metropolis light transport.
+    Mlt,
+    /// Ambient occlusion within `ao_radius`.
+    #[serde(rename = "ao")]
+    AmbientOcclusion,
+    /// Shading normals, after normal and bump maps, as colours.
+    ShadingNormal,
+    /// True surface normals as colours.
+    GeometricNormal,
+    /// Distance to the first hit, mid grey at the focus distance.
+    Depth,
+    /// Texture coordinates in red and green.
+    Uv,
+    /// A colour per distinct material.
+    MaterialId,
+    /// A colour per object.
+    PrimitiveId,
+    /// Heatmap of intersection tests per camera ray.
+    TraversalCost,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            spectral: false,
+            max_depth: 50,
+            rr_depth: 5,
+            integrator: IntegratorKind::Path,
+            sampler: SamplerKind::Independent,
+            filter: Filter::Box,
+            filter_radius: 0.5,
+            photons: 50_000,
+            caustic_photons: 50_000,
+            photon_gather: 50,
+            photon_radius: 0.5,
+            mlt_bootstrap: 1000,
+            mlt_chains: 10,
+            mlt_large_step: 0.3,
+            mlt_sigma: 0.01,
+            clamp_direct: 0.0,
+            clamp_indirect: 0.0,
+            regularize: false,
+            regularize_angle: 10.0,
+            ao_radius: 1.0,
+            adaptive: false,
+            adaptive_threshold: 0.05,
+            adaptive_min_spp: 16,
+            adaptive_max_spp: 512,
+            aovs: Vec::new(),
+            display: DisplaySettings::default(),
+            denoise: DenoiseSettings::default(),
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use crate::vec3::Vec3;
+use std::ops::{AddAssign, Mul, MulAssign};
+
+/// Quantity carried along a path: RGB, or radiance at sampled wavelengths.
+pub trait Spectrum: Copy + AddAssign + Mul<Output = Self> + Mul<f32, Output = Self> {
+    fn max_value(&self) -> f32;
+}
+
+impl Spectrum for Vec3 {
+    fn max_value(&self) -> f32 {
+        self.x().max(self.y()).max(self.z())
+    }
+}
+
+/// How an integrator turns the RGB values stored in the scene into the
+/// quantity its paths carry.
+pub trait ColorMode {
+    type Value: Spectrum;
+
+    fn rgb(&self, rgb: Vec3) -> Self::Value;
+
+    fn constant(&self, c: f32) -> Self::Value;
+
+    /// Brightest channel of the RGB colour a value adds to the image.
+    fn brightness(&self, value: &Self::Value) -> f32;
+
+    /// Wavelength BSDFs should use for wavelength-dependent effects.
+    fn wavelength(&self) -> Option<f32>;
+
+    /// Called after a dispersive scattering event.
+    fn terminate_secondary(&mut self);
+}
+
+/// Plain RGB rendering.
+pub struct Rgb;
+
+impl ColorMode for Rgb {
+    type Value = Vec3;
+
+    fn rgb(&self, rgb: Vec3) -> Vec3 {
+        rgb
+    }
+
+    fn constant(&self, c: f32) -> Vec3 {
+        Vec3::new(c, c, c)
+    }
+
+    fn brightness(&self, value: &Vec3) -> f32 {
+        value.max_value()
+    }
+
+    fn wavelength(&self) -> Option<f32> {
+        None
+    }
+
+    fn terminate_secondary(&mut self) {}
+}
+
+impl ColorMode for SampledWavelengths {
+    type Value = SampledSpectrum;
+
+    fn rgb(&self, rgb: Vec3) -> SampledSpectrum {
+        SampledSpectrum::from_rgb(rgb, self)
+    }
+
+    fn constant(&self, c: f32) -> SampledSpectrum {
+        SampledSpectrum::constant(c)
+    }
+
+    fn brightness(&self, value: &SampledSpectrum) -> f32 {
+        SampledWavelengths::to_rgb(*self, value).max_value()
+    }
+
+    fn wavelength(&self) -> Option<f32> {
+        Some(self.hero())
+    }
+
+    fn terminate_secondary(&mut self) {
+        SampledWavelengths::terminate_secondary(self)
+    }
+}
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 780.0;
+pub const N_SPECTRUM_SAMPLES: usize = 4;
+
+/// Integral of the fitted CIE y matching function over the sampled range.
+const CIE_Y_INTEGRAL: f32 = 106.919_73;
+
+/// XYZ to linear sRGB, white-balanced so that a flat spectrum of one maps to
+/// RGB white.
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [3.084_417, -1.537_138_5, -0.543_295_7],
+    [-0.922_593, 1.876_010_8, 0.045_287_41],
+    [0.052_964, -0.204_025_9, 1.152_155_9],
+];
+
+/// Hero wavelength sampling: one uniformly sampled wavelength plus the others
+/// spread evenly across the visible range, so a path carries four at once.
+#[derive(Clone, Copy, Debug)]
+pub struct SampledWavelengths {
+    lambda: [f32; N_SPECTRUM_SAMPLES],
+    pdf: [f32; N_SPECTRUM_SAMPLES],
+}
+
+impl SampledWavelengths {
+    pub fn sample_uniform(u: f32) -> SampledWavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let delta = range / N_SPECTRUM_SAMPLES as f32;
+        let mut lambda = [0.0; N_SPECTRUM_SAMPLES];
+        lambda[0] = LAMBDA_MIN + u * range;
+        for i in 1..N_SPECTRUM_SAMPLES {
+            lambda[i] = lambda[i - 1] + delta;
+            if lambda[i] > LAMBDA_MAX {
+                lambda[i] -= range;
+            }
+        }
+        SampledWavelengths {
+            lambda,
+            pdf: [1.0 / range; N_SPECTRUM_SAMPLES],
+        }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    /// Drops every wavelength but the hero, for when a path has taken a
+    /// wavelength-dependent direction such as refraction through dispersive glass.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf.iter_mut().skip(1) {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= N_SPECTRUM_SAMPLES as f32;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&p| p == 0.0)
+    }
+
+    /// Monte Carlo estimate of the linear RGB colour of a sampled spectrum.
+    pub fn to_rgb(self, s: &SampledSpectrum) -> Vec3 {
+        let mut xyz = [0.0; 3];
+        for i in 0..N_SPECTRUM_SAMPLES {
+            if self.pdf[i] == 0.0 {
+                continue;
+            }
+            let l = s.0[i] / self.pdf[i];
+            let cmf = cie_xyz(self.lambda[i]);
+            for c in 0..3 {
+                xyz[c] += cmf[c] * l;
+            }
+        }
+        let scale = 1.0 / (N_SPECTRUM_SAMPLES as f32 * CIE_Y_INTEGRAL);
+        let m = &XYZ_TO_RGB;
+        let rgb = |row: &[f32; 3]| (row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2]) * scale;
+        Vec3::new(rgb(&m[0]), rgb(&m[1]), rgb(&m[2]))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum(pub [f32; N_SPECTRUM_SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn constant(c: f32) -> SampledSpectrum {
+        SampledSpectrum([c; N_SPECTRUM_SAMPLES])
+    }
+
+    /// Upsamples an RGB triple at the given wavelengths.
+    pub fn from_rgb(rgb: Vec3, lambda: &SampledWavelengths) -> SampledSpectrum {
+        let mut s = [0.0; N_SPECTRUM_SAMPLES];
+        for (v, &l) in s.iter_mut().zip(lambda.lambda.iter()) {
+            *v = rgb_to_spectrum(rgb, l);
+        }
+        SampledSpectrum(s)
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: Self) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let mut s = self.0;
+        for (a, b) in s.iter_mut().zip(other.0.iter()) {
+            *a *= b;
+        }
+        SampledSpectrum(s)
+    }
+}
+
+impl Mul<f32> for SampledSpectrum {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        let mut s = self.0;
+        for a in s.iter_mut() {
+            *a *= rhs;
+        }
+        SampledSpectrum(s)
+    }
+}
+
+impl MulAssign for SampledSpectrum {
+    fn mul_assign(&mut self, other: Self) {
+        *self = *self * other;
+    }
+}
+
+impl Spectrum for SampledSpectrum {
+    fn max_value(&self) -> f32 {
+        self.0.iter().cloned().fold(f32::MIN, f32::max)
+    }
+}
+
+/// Rec. 709 luminance of a linear RGB value.
+pub fn luminance(c: &Vec3) -> f32 {
+    0.2126 * c.r() + 0.7152 * c.g() + 0.0722 * c.b()
+}
+
+/// Wyman, Sloan and Shirley's multi-lobe Gaussian fit of the CIE 1931
+/// colour matching functions.
+pub fn cie_xyz(lambda: f32) -> [f32; 3] {
+    let g = |mu: f32, s1: f32, s2: f32| {
+        let t = (lambda - mu) / if lambda < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ]
+}
+
+const SMITS_WHITE: [f32; 10] = [1.0, 1.0, 0.9999, 0.9993, 0.9992, 0.9998, 1.0, 1.0, 1.0, 1.0];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0, 0.0, 0.0];
+const SMITS_MAGENTA: [f32; 10] = [1.0, 1.0, 0.9685, 0.2229, 0.0, 0.0458, 0.8369, 1.0, 1.0, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0, 0.1088, 0.6651, 1.0, 1.0, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0, 0.0, 0.0, 0.0, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0, 0.0, 0.0273, 0.7937, 1.0, 0.9418, 0.1719, 0.0, 0.0, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0, 1.0, 0.8916, 0.3323, 0.0, 0.0, 0.0003, 0.0369, 0.0483, 0.0496];
+
+/// Smits' RGB to spectrum conversion, evaluated at a single wavelength.
+pub fn rgb_to_spectrum(rgb: Vec3, lambda: f32) -> f32 {
+    let t = (lambda - LAMBDA_MIN) / (720.0 - LAMBDA_MIN);
+    let bin = ((t * 10.0) as usize).min(9);
+    let (r, g, b) = (rgb.r(), rgb.g(), rgb.b());
+    if r <= g && r <= b {
+        let mut s = r * SMITS_WHITE[bin];
+        if g <= b {
+            s += (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin];
+        } else {
+            s += (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin];
+        }
+        s
+    } else if g <= r && g <= b {
+        let mut s = g * SMITS_WHITE[bin];
+        if r <= b {
+            s += (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin];
+        } else {
+            s += (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin];
+        }
+        s
+    } else {
+        let mut s = b * SMITS_WHITE[bin];
+        if r <= g {
+            s += (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin];
+        } else {
+            s += (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin];
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SampledSpectrum, SampledWavelengths, Vec3};
+
+    #[test]
+    fn white_round_trip() {
+        let mut rgb = Vec3::new(0.0, 0.0, 0.0);
+        let n = 1000;
+        for i in 0..n {
+            let lambda = SampledWavelengths::sample_uniform((i as f32 + 0.5) / n as f32);
+            let s = SampledSpectrum::from_rgb(Vec3::new(1.0, 1.0, 1.0), &lambda);
+            rgb += lambda.to_rgb(&s);
+        }
+        rgb /= n as f32;
+        assert!((rgb - Vec3::new(1.0, 1.0, 1.0)).length() < 0.01);
+    }
+
+    #[test]
+    fn terminate_secondary_keeps_expectation() {
+        let mut rgb = Vec3::new(0.0, 0.0, 0.0);
+        let n = 1000;
+        for i in 0..n {
+            let mut lambda = SampledWavelengths::sample_uniform((i as f32 + 0.5) / n as f32);
+            lambda.terminate_secondary();
+            assert!(lambda.secondary_terminated());
+            rgb += lambda.to_rgb(&SampledSpectrum::constant(1.0));
+        }
+        rgb /= n as f32;
+        assert!((rgb - Vec3::new(1.0, 1.0, 1.0)).length() < 0.01);
+    }
+}
@@ -1,8 +1,11 @@
 use crate::hitable::{HitRecord, Hitable};
 use crate::material::Material;
+use crate::onb::Onb;
 use crate::ray::Ray;
-use crate::vec3::Vec3;
+use crate::sampler::Sampler;
+use crate::vec3::{random_unit_vector, Vec3};
 use serde::{Serialize, Deserialize};
+use std::f32::consts::PI;
 
 #[derive(Serialize, Deserialize)]
 pub struct Sphere {
@@ -19,39 +22,197 @@ impl Sphere {
             material,
         }
     }
-}
 
-impl Hitable for Sphere {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut rec: HitRecord = HitRecord {
-            t: t_max,
-            p: Vec3::new(0.0, 0.0, 0.0),
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn record(&self, t: f32, p: Vec3) -> HitRecord<'_> {
+        let mut rec = HitRecord {
+            t,
+            p,
             normal: Vec3::new(0.0, 0.0, 0.0),
-            material: self.material.clone(),
+            geometric_normal: Vec3::new(0.0, 0.0, 0.0),
+            dpdu: Vec3::new(0.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
+            material: &self.material,
+            wavelength: None,
         };
+        self.surface(&mut rec);
+        rec
+    }
 
+    fn surface(&self, rec: &mut HitRecord) {
+        let local = rec.p - self.center;
+        rec.normal = local / self.radius;
+        rec.geometric_normal = rec.normal;
+        let (u, v) = sphere_uv(&rec.normal);
+        rec.u = u;
+        rec.v = v;
+        // u runs against phi and v runs up with theta
+        rec.dpdu = 2.0 * PI * Vec3::new(local.z(), 0.0, -local.x());
+        let r_xz = (local.x() * local.x() + local.z() * local.z()).sqrt();
+        if r_xz > 0.0 {
+            let cos_phi = local.x() / r_xz;
+            let sin_phi = local.z() / r_xz;
+            rec.dpdv = PI * Vec3::new(-local.y() * cos_phi, r_xz, -local.y() * sin_phi);
+        } else {
+            // At the poles pick any tangent frame
+            rec.dpdu = 2.0 * PI * self.radius * Vec3::new(0.0, 0.0, -1.0);
+            rec.dpdv = PI * self.radius * Vec3::new(-rec.normal.y(), 0.0, 0.0);
+        }
+    }
+}
+
+impl Hitable for Sphere {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc: Vec3 = r.origin() - self.center;
         let a: f32 = r.direction().dot(&r.direction());
         let b: f32 = oc.dot(&r.direction());
         let c: f32 = oc.dot(&oc) - self.radius * self.radius;
-        let discriminant: f32 = b * b - a * c;
+        // b * b - a * c cancels badly for rays starting far away, so measure
+        // the discriminant from the ray's closest approach instead
+        let l: Vec3 = oc - (b / a) * r.direction();
+        let discriminant: f32 = a * (self.radius * self.radius - l.dot(&l));
 
         if discriminant > 0.0 {
-            let temp: f32 = (-b - (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_parameter(rec.t);
-                rec.normal = (rec.p - self.center) / self.radius;
-                return Some(rec);
-            }
-            let temp: f32 = (-b + (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_parameter(rec.t);
-                rec.normal = (rec.p - self.center) / self.radius;
-                return Some(rec);
+            let q: f32 = -(b + b.signum() * discriminant.sqrt());
+            let (near, far) = if q == 0.0 {
+                (0.0, 0.0)
+            } else {
+                let (t0, t1) = (c / q, q / a);
+                (t0.min(t1), t0.max(t1))
+            };
+            for &temp in &[near, far] {
+                if temp < t_max && temp > t_min {
+                    let rec = self.record(temp, r.point_at_parameter(temp));
+                    if self.material.opaque(&rec) {
+                        return Some(rec);
+                    }
+                }
             }
         }
         None
     }
+
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        let distance_squared = (self.center - *o).squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 0.0;
+        }
+        if self.hit(&Ray::new(*o, *v), 0.001, f32::MAX).is_none() {
+            return 0.0;
+        }
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
+
+    /// Samples the cone of directions the sphere subtends from `o`.
+    fn random(&self, o: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.center - *o;
+        let distance_squared = direction.squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return direction;
+        }
+        let (r1, r2) = sampler.next_2d();
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let z = 1.0 + r2 * (cos_theta_max - 1.0);
+        let phi = 2.0 * PI * r1;
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+        let uvw = Onb::from_w(&direction);
+        uvw.to_world(&Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z))
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord<'_>, f32)> {
+        let n = random_unit_vector(sampler);
+        let rec = self.record(0.0, self.center + self.radius * n);
+        // Cut-away parts of the surface don't emit
+        if !self.material.opaque(&rec) {
+            return None;
+        }
+        let pdf = self.surface_pdf(&rec.p);
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, p: &Vec3) -> f32 {
+        let distance = (*p - self.center).length();
+        if (distance - self.radius).abs() > 1e-3 * self.radius {
+            return 0.0;
+        }
+        1.0 / (4.0 * PI * self.radius * self.radius)
+    }
+
+    fn bounding_sphere(&self) -> Option<(Vec3, f32)> {
+        Some((self.center, self.radius))
+    }
+
+    fn primitive_material(&self) -> Option<&Material> {
+        Some(&self.material)
+    }
+}
+
+fn sphere_uv(p: &Vec3) -> (f32, f32) {
+    let phi = p.z().atan2(p.x());
+    let theta = p.y().clamp(-1.0, 1.0).asin();
+    (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sphere, Vec3};
+    use crate::hitable::Hitable;
+    use crate::material::{Cutout, Lambertian, Material};
+    use crate::ray::Ray;
+    use crate::texture::Texture;
+
+    fn lambertian() -> Material {
+        Material::Lambertian {
+            mat: Lambertian::new(0.5, 0.5, 0.5),
+        }
+    }
+
+    #[test]
+    fn hit() {
+        let s = Sphere::new(Vec3::new(0.0, 0.0, -2.0), 1.0, lambertian());
+        let r = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = s.hit(&r, 0.001, f32::MAX).unwrap();
+        assert_eq!(rec.t, 1.0);
+        assert_eq!(rec.normal, Vec3::new(0.0, 0.0, 1.0));
+        assert!(rec.dpdu.cross(&rec.dpdv).dot(&rec.normal) > 0.0);
+    }
+
+    #[test]
+    fn hit_from_far_away() {
+        let s = Sphere::new(Vec3::new(0.0, 0.0, 0.0), 40.0, lambertian());
+        let r = Ray::new(Vec3::new(0.3, 2000.0, 0.2), Vec3::new(0.0, -1.0, 0.0));
+        let rec = s.hit(&r, 0.001, f32::MAX).unwrap();
+        assert!(((rec.p.length() - 40.0) / 40.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn cutout_skips_to_far_side() {
+        // Cut away the front half of the sphere so the ray hits the back wall
+        let s = Sphere::new(
+            Vec3::new(0.0, 0.0, -2.0),
+            1.0,
+            Material::Cutout {
+                mat: Cutout::new(
+                    lambertian(),
+                    Texture::Checker {
+                        odd: Vec3::new(0.0, 0.0, 0.0),
+                        even: Vec3::new(1.0, 1.0, 1.0),
+                        scale: std::f32::consts::PI / 2.0,
+                    },
+                    Some(0.5),
+                ),
+            },
+        );
+        let r = Ray::new(Vec3::new(0.1, 0.1, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let rec = s.hit(&r, 0.001, f32::MAX).unwrap();
+        assert!(rec.p.z() < -2.0);
+    }
 }
@@ -0,0 +1,102 @@
+use crate::vec3::Vec3;
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Texture {
+    Constant { color: Vec3 },
+    Checker { odd: Vec3, even: Vec3, scale: f32 },
+    Image { width: u32, height: u32, data: Vec<Vec3> },
+}
+
+impl Texture {
+    pub fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        match self {
+            Texture::Constant { color } => *color,
+            Texture::Checker { odd, even, scale } => {
+                let sines = (scale * p.x()).sin() * (scale * p.y()).sin() * (scale * p.z()).sin();
+                if sines < 0.0 {
+                    *odd
+                } else {
+                    *even
+                }
+            }
+            Texture::Image { width, height, data } => {
+                // Scene JSON can hold any sizes, so a missing or mis-sized
+                // image shows up cyan rather than panicking
+                if *width == 0 || *height == 0 || data.len() != (*width as usize) * (*height as usize) {
+                    return Vec3::new(0.0, 1.0, 1.0);
+                }
+                let u = u.clamp(0.0, 1.0);
+                let v = 1.0 - v.clamp(0.0, 1.0);
+                let i = ((u * *width as f32) as u32).min(width - 1);
+                let j = ((v * *height as f32) as u32).min(height - 1);
+                data[(j * width + i) as usize]
+            }
+        }
+    }
+
+    /// Scalar lookup used for masks, averaging the three channels.
+    pub fn mask(&self, u: f32, v: f32, p: &Vec3) -> f32 {
+        let c = self.value(u, v, p);
+        (c.x() + c.y() + c.z()) / 3.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Texture;
+    use super::Vec3;
+
+    #[test]
+    fn constant() {
+        let t = Texture::Constant {
+            color: Vec3::new(0.25, 0.5, 0.75),
+        };
+        assert_eq!(t.value(0.3, 0.7, &Vec3::new(1.0, 2.0, 3.0)), Vec3::new(0.25, 0.5, 0.75));
+        assert_eq!(t.mask(0.0, 0.0, &Vec3::new(0.0, 0.0, 0.0)), 0.5);
+    }
+
+    #[test]
+    fn checker() {
+        let t = Texture::Checker {
+            odd: Vec3::new(0.0, 0.0, 0.0),
+            even: Vec3::new(1.0, 1.0, 1.0),
+            scale: 10.0,
+        };
+        assert_eq!(t.value(0.0, 0.0, &Vec3::new(0.1, 0.1, 0.1)), Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(t.value(0.0, 0.0, &Vec3::new(-0.1, 0.1, 0.1)), Vec3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn image() {
+        let t = Texture::Image {
+            width: 2,
+            height: 2,
+            data: vec![
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ],
+        };
+        let p = Vec3::new(0.0, 0.0, 0.0);
+        // v runs bottom to top, rows are stored top to bottom
+        assert_eq!(t.value(0.25, 0.75, &p), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(t.value(0.75, 0.25, &p), Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(t.value(1.0, 1.0, &p), Vec3::new(0.0, 1.0, 0.0));
+
+        let cyan = Vec3::new(0.0, 1.0, 1.0);
+        let short = Texture::Image {
+            width: 2,
+            height: 2,
+            data: vec![Vec3::new(1.0, 0.0, 0.0)],
+        };
+        assert_eq!(short.value(0.75, 0.25, &p), cyan);
+        let empty = Texture::Image {
+            width: 0,
+            height: 3,
+            data: Vec::new(),
+        };
+        assert_eq!(empty.value(0.5, 0.5, &p), cyan);
+    }
+}
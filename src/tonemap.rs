@@ -0,0 +1,126 @@
+use crate::settings::DisplaySettings;
+use crate::vec3::Vec3;
+use serde::{Serialize, Deserialize};
+
+/// Curve squeezing HDR radiance into the displayable range.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapper {
+    /// Cut everything above 1.
+    Clamp,
+    /// `c / (1 + c)` per channel.
+    Reinhard,
+    /// Reinhard rescaled so `white_point` maps to 1.
+    ExtendedReinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+    /// AgX-style log encoding with a sigmoid, which desaturates highlights
+    /// towards white instead of skewing their hue.
+    Agx,
+}
+
+impl ToneMapper {
+    /// Maps linear radiance to linear display values in [0, 1].
+    pub fn apply(&self, c: Vec3, white_point: f32) -> Vec3 {
+        match self {
+            ToneMapper::Clamp => per_channel(c, |x| x.min(1.0)),
+            ToneMapper::Reinhard => per_channel(c, |x| x / (1.0 + x)),
+            ToneMapper::ExtendedReinhard => {
+                let w2 = (white_point * white_point).max(1e-6);
+                per_channel(c, |x| (x * (1.0 + x / w2) / (1.0 + x)).min(1.0))
+            }
+            ToneMapper::Aces => per_channel(c, |x| {
+                ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+            }),
+            ToneMapper::Agx => agx(c),
+        }
+    }
+}
+
+/// The display pipeline short of quantisation, giving sRGB-encoded values
+/// in [0, 1] for output at any bit depth.
+pub fn display_value(c: Vec3, settings: &DisplaySettings) -> Vec3 {
+    let c = per_channel(c, |x| x.max(0.0)) * settings.exposure.exp2();
+    let mapped = settings.tone_mapper.apply(c, settings.white_point);
+    per_channel(mapped, |x| srgb_oetf(x).clamp(0.0, 1.0))
+}
+
+/// sRGB opto-electronic transfer function, linear to encoded.
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn per_channel<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(c.r()), f(c.g()), f(c.b()))
+}
+
+/// Matrix times vector, with the matrix given by columns.
+fn transform(columns: &[[f32; 3]; 3], c: Vec3) -> Vec3 {
+    let mut out = [0.0; 3];
+    for (j, column) in columns.iter().enumerate() {
+        for i in 0..3 {
+            out[i] += column[i] * c[j];
+        }
+    }
+    Vec3::new(out[0], out[1], out[2])
+}
+
+/// Minimal AgX: compress the gamut inwards, encode in log2 over about 16.5
+/// stops, apply the default contrast sigmoid and decode back to linear.
+fn agx(c: Vec3) -> Vec3 {
+    const INSET: [[f32; 3]; 3] = [
+        [0.842_479_06, 0.042_328_24, 0.042_375_655],
+        [0.078_433_6, 0.878_468_6, 0.078_433_6],
+        [0.079_223_745, 0.079_166_13, 0.879_143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196_879, -0.052_896_852, -0.052_971_635],
+        [-0.098_020_88, 1.151_903_1, -0.098_043_45],
+        [-0.099_029_74, -0.098_961_18, 1.151_073_7],
+    ];
+    const MIN_EV: f32 = -12.473_93;
+    const MAX_EV: f32 = 4.026_069;
+
+    let encoded = per_channel(transform(&INSET, c), |x| {
+        let ev = x.max(1e-10).log2().clamp(MIN_EV, MAX_EV);
+        let x = (ev - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    });
+    per_channel(transform(&OUTSET, encoded), |x| x.max(0.0).powf(2.2).min(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{srgb_oetf, ToneMapper};
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn tone_mappers_stay_in_range_and_increase() {
+        let mappers = [
+            ToneMapper::Clamp,
+            ToneMapper::Reinhard,
+            ToneMapper::ExtendedReinhard,
+            ToneMapper::Aces,
+            ToneMapper::Agx,
+        ];
+        for m in mappers.iter() {
+            let mut last = -1.0;
+            for i in 0..200 {
+                let x = 0.01 * (i * i) as f32;
+                let y = m.apply(Vec3::new(x, x, x), 4.0).g();
+                assert!((0.0..=1.0).contains(&y), "{:?} {} -> {}", m, x, y);
+                assert!(y >= last - 1e-4, "{:?} not monotonic at {}", m, x);
+                last = y;
+            }
+        }
+        assert!((ToneMapper::ExtendedReinhard.apply(Vec3::new(4.0, 4.0, 4.0), 4.0).r() - 1.0).abs() < 1e-6);
+        assert!((srgb_oetf(0.5) - 0.735_357).abs() < 1e-4);
+        assert_eq!(srgb_oetf(0.0), 0.0);
+    }
+}
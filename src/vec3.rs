@@ -1,5 +1,5 @@
-use rand::prelude::*;
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use crate::sampler::Sampler;
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub, SubAssign};
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
@@ -220,16 +220,40 @@ impl Neg for Vec3 {
     }
 }
 
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut p: Vec3;
-    let mut rng = thread_rng();
-    loop {
-        p = 2.0 * Vec3::new(rng.gen(), rng.gen(), rng.gen()) - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_length() < 1.0 {
-            break;
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis out of range: {}", axis),
         }
     }
-    p
+}
+
+/// Uniform point inside the unit ball.
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    let direction = random_unit_vector(sampler);
+    direction * sampler.next_1d().cbrt()
+}
+
+/// Uniform direction over the whole sphere.
+pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let z = 1.0 - 2.0 * r2;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    Vec3::new(phi.cos() * r, phi.sin() * r, z)
+}
+
+/// Cosine-weighted direction in a local frame with the normal along +z.
+pub fn random_cosine_direction(sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let r = r2.sqrt();
+    Vec3::new(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
 }
 
 pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
@@ -1,5 +1,6 @@
 const submitButton = document.querySelector("#submitButton");
 const saveButton = document.querySelector("#downloadButton");
+const stopButton = document.querySelector("#stopButton");
 const result = document.querySelector("#result");
 const canvas = document.getElementById('canvas');
 const ctx = canvas.getContext('2d');
@@ -11,16 +12,114 @@ const imageSizes = {
   "5": [1920, 1080]
 };
 
-saveButton.addEventListener('click', () => {
-    let dataURL = canvas.toDataURL('image/png');
+// The world picked in the scene menu, as JSON for the workers
+const sceneJson = wasm =>
+  document.getElementById('scene').value === "demo" ? wasm.demo_scene_json() : wasm.scene_gen_json();
+
+// How the image on the canvas was made, written into saved PNGs
+let imageText = [];
+
+saveButton.addEventListener('click', async () => {
+    const wasm = await import("../../pkg");
+    const pixels = ctx.getImageData(0, 0, canvas.width, canvas.height).data;
+    let png;
+    try {
+      png = wasm.encode_png(canvas.width, canvas.height, pixels, imageText);
+    } catch (error) {
+      result.textContent = "Could not save the image: " + error;
+      return;
+    }
+    const url = URL.createObjectURL(new Blob([png], { type: 'image/png' }));
     let tmpLink = document.createElement('a');
     tmpLink.download = 'image.png'
-    tmpLink.href = dataURL;
+    tmpLink.href = url;
     document.body.appendChild(tmpLink);
     tmpLink.click();
     document.body.removeChild(tmpLink);
+    URL.revokeObjectURL(url);
+});
+
+const describe = (settings, stats) => [
+  ["Software", "rust_raytracer"],
+  ["Settings", JSON.stringify(settings)],
+  ["Render stats", stats]
+];
+
+
+const displaySettings = () => ({
+  exposure: Number(document.getElementById('exposure').value),
+  tone_mapper: document.getElementById('toneMapper').value
 });
 
+// Denoiser for progressive passes, or null for none
+const denoiseSettings = () => {
+  const method = document.getElementById('denoise').value;
+  return method ? { method: method } : null;
+};
+
+// The progressive worker keeps its HDR image, so display changes can be
+// shown without rendering again
+let progressiveWorker = null;
+for (const id of ['exposure', 'toneMapper', 'denoise']) {
+  document.getElementById(id).addEventListener('change', () => {
+    if (progressiveWorker !== null) {
+      progressiveWorker.postMessage({
+        display: displaySettings(),
+        denoise: denoiseSettings()
+      });
+    }
+  });
+}
+
+let stopped = false;
+stopButton.addEventListener('click', () => {
+  stopped = true;
+});
+
+// Renders one pass at a time on a single worker, showing each as it lands,
+// until the stop button is pressed
+async function progressive(WIDTH, HEIGHT, settings) {
+  const worker = new Worker("./worker.js");
+  await loaded(worker);
+  progressiveWorker = worker;
+  stopped = false;
+  stopButton.style.display = "initial";
+  const t0 = performance.now();
+
+  worker.addEventListener("message", ev => {
+    const message = ev.data;
+    if (message.allGood === "ready") {
+      worker.postMessage({ pass: true, denoise: denoiseSettings() });
+    } else if (message.allGood === false) {
+      result.textContent = "Something went wrong! " + message.error;
+    } else if (message.redisplay) {
+      const pixels = new Uint8ClampedArray(message.image);
+      ctx.putImageData(new ImageData(pixels, WIDTH, HEIGHT), 0, 0);
+    } else if (message.pass) {
+      const pixels = new Uint8ClampedArray(message.image);
+      ctx.putImageData(new ImageData(pixels, WIDTH, HEIGHT), 0, 0);
+      const t1 = performance.now();
+      result.textContent = `${message.pass} samples per pixel in ${t1 - t0} ms`;
+      imageText = describe(settings, `${WIDTH}x${HEIGHT}, ${message.pass} samples per pixel, ${t1 - t0} ms`);
+      saveButton.style.display = "initial";
+      if (stopped) {
+        stopButton.style.display = "none";
+        submitButton.disabled = false;
+      } else {
+        worker.postMessage({ pass: true, denoise: denoiseSettings() });
+      }
+    }
+  });
+
+  const wasm = await import("../../pkg");
+  worker.postMessage({
+    init: true,
+    width: WIDTH,
+    height: HEIGHT,
+    world: sceneJson(wasm),
+    settings: settings
+  });
+}
 
 submitButton.addEventListener("click", async () => {
   const [WIDTH, HEIGHT] = imageSizes[document.getElementById('imageSize')
@@ -35,7 +134,28 @@ submitButton.addEventListener("click", async () => {
   submitButton.disabled = true;
   const workerCount = Number(document.getElementById('workerNum')
     .value);
+  const settings = {
+    spectral: document.getElementById('spectral').checked,
+    adaptive: document.getElementById('adaptive').checked,
+    integrator: document.getElementById('integrator').value,
+    sampler: document.getElementById('sampler').value,
+    filter: document.getElementById('filter').value,
+    filter_radius: Number(document.getElementById('filterRadius').value),
+    clamp_indirect: Number(document.getElementById('clampIndirect').value),
+    regularize: document.getElementById('regularize').checked,
+    display: displaySettings()
+  };
+  if (progressiveWorker !== null) {
+    progressiveWorker.terminate();
+    progressiveWorker = null;
+  }
+  if (document.getElementById('progressive').checked) {
+    progressive(WIDTH, HEIGHT, settings);
+    return;
+  }
   let workers = [];
+  let merged = null;
+  let pending = 0;
   for (let i = 0; i < workerCount; i++) {
     workers[i] = new Worker("./worker.js");
   }
@@ -60,6 +180,21 @@ submitButton.addEventListener("click", async () => {
         });
       } else if (message.allGood === false) {
         result.textContent = "Something went wrong! " + message.error;
+      } else if (message.accumulation) {
+        // Workers' films overlap where the filter spread samples into rows
+        // rendered elsewhere; the first worker adds the others to its own
+        const acc = message.accumulation;
+        merged = merged || new Float32Array(acc.length);
+        for (let j = 0; j < acc.length; j++) {
+          merged[j] += acc[j];
+        }
+        pending--;
+        if (pending === 0) {
+          workers[0].postMessage({ merge: merged });
+        }
+      } else if (message.merged) {
+        const pixels = new Uint8ClampedArray(message.image);
+        ctx.putImageData(new ImageData(pixels, WIDTH, HEIGHT), 0, 0);
       } else {
         received++;
         count--;
@@ -82,7 +217,19 @@ submitButton.addEventListener("click", async () => {
           ctx.putImageData(imageData, 0, 0);
           let t1 = performance.now();
           result.textContent = `done in ${t1 - t0} ms`;
+          imageText = describe(settings, `${WIDTH}x${HEIGHT}, ${settings.adaptive ? "adaptive" : "100"} samples per pixel, ${workerCount} workers, ${t1 - t0} ms`);
             saveButton.style.display = "initial";
+          // Rows shown as they finished miss what later rows spread into
+          // them, and the light paths each other worker traced, so wide
+          // filters and bidirectional path tracing need the workers' films
+          // combined
+          if (settings.filter_radius > 0.5 || settings.integrator === "bdpt") {
+            pending = workers.length - 1;
+            if (pending === 0) {
+              workers[0].postMessage({ merge: new Float32Array(0) });
+            }
+            workers.slice(1).forEach(w => w.postMessage({ accumulation: true }));
+          }
         }
       }
     });
@@ -91,13 +238,14 @@ submitButton.addEventListener("click", async () => {
 
   import("../../pkg")
     .then(wasm => {
-      const world = wasm.scene_gen_json();
+      const world = sceneJson(wasm);
       for (let i = 0; i < workers.length; i++) {
         workers[i].postMessage({
           init: true,
           width: WIDTH,
           height: HEIGHT,
-          world: world
+          world: world,
+          settings: settings
         });
       }
     });
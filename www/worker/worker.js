@@ -6,15 +6,50 @@ import("../../pkg")
     let scene;
 
     self.addEventListener("message", ev => {
-      const msg = ev.data;
+      try {
+        handle(ev.data);
+      } catch (error) {
+        self.postMessage({
+          allGood: false,
+          error: String(error)
+        });
+      }
+    });
+
+    const handle = msg => {
       if (msg.init) {
         WIDTH = msg.width;
         HEIGHT = msg.height;
         world = msg.world;
-        scene = wasm.Scene.new(WIDTH, HEIGHT, world);
+        scene = wasm.Scene.with_settings(WIDTH, HEIGHT, world, msg.settings);
         self.postMessage({
           allGood: "ready"
         });
+      } else if (msg.display) {
+        scene.set_display(msg.display);
+        self.postMessage({
+          redisplay: true,
+          image: msg.denoise ? scene.denoise(msg.denoise) : scene.display()
+        });
+      } else if (msg.pass) {
+        let image = scene.render_pass();
+        if (msg.denoise) {
+          image = scene.denoise(msg.denoise);
+        }
+        self.postMessage({
+          pass: scene.passes(),
+          image: image
+        });
+      } else if (msg.accumulation) {
+        self.postMessage({
+          accumulation: scene.accumulation()
+        });
+      } else if (msg.merge) {
+        scene.add_accumulation(msg.merge);
+        self.postMessage({
+          merged: true,
+          image: scene.display()
+        });
       } else if (msg.job) {
         let row = scene.image_row(msg.count);
         self.postMessage({
@@ -22,6 +57,6 @@ import("../../pkg")
           imgRow: row
         });
       }
-    });
+    };
       self.postMessage("loaded");
   });